@@ -0,0 +1,97 @@
+//! Times compiling a large generated chart.
+//!
+//! Run with ```cargo bench```.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use bms_parser::{
+    chart::BmsChart, keymode::KeyMode, keysounds::generate_keysounds, notes::generate_notes,
+    rng::SeededRng, timing::generate_timings, timing::TimingMap,
+};
+
+/// A chart with a lot of everything: headers, long measures
+/// on every note channel and a few random blocks
+fn large_chart() -> String {
+    let mut data = String::new();
+    data.push_str("#PLAYER 1\n#TITLE Benchmark\n#ARTIST bms_parser\n#BPM 150\n#PLAYLEVEL 12\n");
+    for id in 1..1296 {
+        data.push_str(&format!("#WAV{} sound{}.wav\n", base36(id), id));
+    }
+    for id in 1..100 {
+        data.push_str(&format!("#BPM{:02} {}\n", id, 100 + id));
+    }
+    let channels = [
+        "01", "11", "12", "13", "14", "15", "16", "18", "19", "51", "52", "08",
+    ];
+    for measure in 0..999 {
+        if measure % 50 == 0 {
+            data.push_str("#RANDOM 2\n#IF 1\n");
+        }
+        for channel in channels {
+            data.push_str(&format!("#{:03}{}:", measure, channel));
+            for division in 0..32 {
+                let value = (measure * 31 + division * 7) % 1296;
+                data.push_str(&base36(if division % 3 == 0 { value } else { 0 }));
+            }
+            data.push('\n');
+        }
+        if measure % 50 == 0 {
+            data.push_str("#ENDIF\n#ENDRANDOM\n");
+        }
+    }
+    data
+}
+
+fn base36(value: usize) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let high = DIGITS[value / 36 % 36] as char;
+    let low = DIGITS[value % 36] as char;
+    format!("{}{}", high, low)
+}
+
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    f();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let average = start.elapsed() / iterations;
+    println!("{:<24} {:>12.3?} per iteration", name, average);
+}
+
+fn main() {
+    let data = large_chart();
+    println!(
+        "chart: {} bytes, {} lines",
+        data.len(),
+        data.lines().count()
+    );
+    let chart = BmsChart::compile(&data, &mut SeededRng::new(0)).unwrap();
+
+    bench("compile", 20, || {
+        black_box(BmsChart::compile(black_box(&data), &mut SeededRng::new(0)).unwrap());
+    });
+    bench("scan_headers", 20, || {
+        black_box(BmsChart::scan_headers(black_box(&data), &mut SeededRng::new(0)).unwrap());
+    });
+    bench("generate_keysounds", 20, || {
+        black_box(generate_keysounds(black_box(&chart)));
+    });
+    bench("generate_notes", 20, || {
+        black_box(generate_notes(black_box(&chart), KeyMode::Key7));
+    });
+    bench("generate_timings", 20, || {
+        black_box(generate_timings(black_box(&chart)).unwrap());
+    });
+    let notes = generate_notes(&chart, KeyMode::Key7);
+    let timing = generate_timings(&chart).unwrap();
+    bench("note seconds", 20, || {
+        let timing_map = TimingMap::new(black_box(&timing), &chart.time_signatures);
+        black_box(
+            timing_map
+                .seconds_sorted(notes.iter().map(|v| v.hit_time))
+                .sum::<f64>(),
+        );
+    });
+}
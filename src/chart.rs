@@ -1,17 +1,30 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+use std::ops::Range;
 
-use ordered_float::OrderedFloat;
-use regex::Regex;
-use strum::{EnumIter, FromRepr, IntoEnumIterator};
 use unicase::UniCase;
 
+use super::encoding::{decode_chart, BmsEncoding};
+use super::lexer::{BmsBase, BmsIdKind};
+use super::rng::{BmsRng, BmsRoll};
+use super::template::{self, BmsTemplate};
 use super::timing::BmsTime;
 
+// Objects are only equal when they share a channel and a time (see
+// `update_objects`), but they're still hashed and sorted by every field.
+#[allow(clippy::derived_hash_with_manual_eq, clippy::derive_ord_xor_partial_ord)]
 #[derive(Eq, Hash, Debug, Clone, Copy, Ord)]
 pub struct BmsObject {
     pub channel: u16,
     pub time: BmsTime,
+    /// The id the object points to, decoded in the ```#BASE``` of
+    /// the chart. Channel 03 is the exception: it's the BPM itself,
+    /// decoded from hexadecimal.
     pub value: u16,
+    /// How many cells the ```#xxxCC:``` line of the object is split
+    /// into, the object being in one of them
+    pub divisions: u32,
 }
 
 impl PartialEq for BmsObject {
@@ -20,37 +33,312 @@ impl PartialEq for BmsObject {
     }
 }
 
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for BmsObject {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match self.time.partial_cmp(&other.time) {
             Some(core::cmp::Ordering::Equal) => {
                 if self.value == other.value && self.channel == other.channel {
-                    return Some(core::cmp::Ordering::Equal);
+                    Some(core::cmp::Ordering::Equal)
                 } else {
-                    return None;
+                    None
                 }
             }
-            ord => return ord,
+            ord => ord,
         }
     }
 }
 
+/// An error found while compiling a ```BmsChart```.
+///
+/// Every variant carries the 1-based ```line``` it was found on,
+/// the 1-based (end exclusive) ```columns``` of the offending
+/// text in that line and the offending ```text``` itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BmsParseError {
+    /// The measure number of a ```#xxxCC:``` line isn't valid
+    InvalidMeasure {
+        line: usize,
+        columns: Range<usize>,
+        text: String,
+    },
+    /// The channel of a ```#xxxCC:``` line isn't valid base 36
+    InvalidChannel {
+        line: usize,
+        columns: Range<usize>,
+        text: String,
+    },
+    /// An object pair in a ```#xxxCC:``` line isn't valid base 36
+    InvalidObject {
+        line: usize,
+        columns: Range<usize>,
+        text: String,
+    },
+    /// The value of a ```#xxx02:``` line isn't a number
+    InvalidTimeSignature {
+        line: usize,
+        columns: Range<usize>,
+        text: String,
+    },
+    /// An ```#IF``` was found without a ```#RANDOM``` before it
+    OrphanedIf {
+        line: usize,
+        columns: Range<usize>,
+        text: String,
+    },
+    /// The bound of a ```#RANDOM``` or ```#SWITCH``` (or the value
+    /// of an ```#IF```, ```#SETSWITCH``` or ```#CASE```) doesn't fit
+    /// in a ```u32```
+    InvalidRandom {
+        line: usize,
+        columns: Range<usize>,
+        text: String,
+    },
+}
+
+impl BmsParseError {
+    /// The 1-based line the error was found on
+    pub fn line(&self) -> usize {
+        match self {
+            Self::InvalidMeasure { line, .. }
+            | Self::InvalidChannel { line, .. }
+            | Self::InvalidObject { line, .. }
+            | Self::InvalidTimeSignature { line, .. }
+            | Self::OrphanedIf { line, .. }
+            | Self::InvalidRandom { line, .. } => *line,
+        }
+    }
+
+    /// The 1-based, end exclusive, columns of the offending text
+    pub fn columns(&self) -> Range<usize> {
+        match self {
+            Self::InvalidMeasure { columns, .. }
+            | Self::InvalidChannel { columns, .. }
+            | Self::InvalidObject { columns, .. }
+            | Self::InvalidTimeSignature { columns, .. }
+            | Self::OrphanedIf { columns, .. }
+            | Self::InvalidRandom { columns, .. } => columns.clone(),
+        }
+    }
+
+    /// The offending text
+    pub fn text(&self) -> &str {
+        match self {
+            Self::InvalidMeasure { text, .. }
+            | Self::InvalidChannel { text, .. }
+            | Self::InvalidObject { text, .. }
+            | Self::InvalidTimeSignature { text, .. }
+            | Self::OrphanedIf { text, .. }
+            | Self::InvalidRandom { text, .. } => text,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::InvalidMeasure { .. } => "invalid measure number",
+            Self::InvalidChannel { .. } => "invalid channel",
+            Self::InvalidObject { .. } => "invalid object value",
+            Self::InvalidTimeSignature { .. } => "invalid time signature",
+            Self::OrphanedIf { .. } => "#IF without a #RANDOM",
+            Self::InvalidRandom { .. } => "invalid random value",
+        }
+    }
+}
+
+impl fmt::Display for BmsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let columns = self.columns();
+        write!(
+            f,
+            "{} \"{}\" at line {}, columns {}..{}",
+            self.description(),
+            self.text(),
+            self.line(),
+            columns.start,
+            columns.end
+        )
+    }
+}
+
+impl std::error::Error for BmsParseError {}
+
+/// An error found while compiling a ```BmsChart``` from a reader
+#[derive(Debug)]
+pub enum BmsReadError {
+    /// Reading failed
+    Io(std::io::Error),
+    /// Reading worked, but a line couldn't be compiled
+    Parse(BmsParseError),
+}
+
+impl fmt::Display for BmsReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "couldn't read chart: {}", error),
+            Self::Parse(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BmsReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Parse(error) => Some(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for BmsReadError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<BmsParseError> for BmsReadError {
+    fn from(error: BmsParseError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+/// How ```BmsChart::compile_lenient``` and friends react to a
+/// line that can't be compiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BmsParseMode {
+    /// Stop at the first ```BmsParseError```
+    #[default]
+    Strict,
+    /// Skip the broken line or object, record a ```Diagnostic```
+    /// and keep going
+    Lenient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Something that was ignored but is probably harmless,
+    /// like a ```#``` line nobody knows about
+    Info,
+    /// Something broken that was skipped over
+    Warning,
+}
+
+/// Something noteworthy found while leniently compiling a chart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 1-based line the diagnostic is about
+    pub line: usize,
+    pub message: String,
+    /// A short, stable identifier for the kind of diagnostic
+    /// (```"invalid_object"```, ```"unknown_line"```, etc.)
+    pub code: &'static str,
+}
+
+impl BmsParseError {
+    /// A short, stable identifier for the kind of error,
+    /// also used as the ```Diagnostic``` code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidMeasure { .. } => "invalid_measure",
+            Self::InvalidChannel { .. } => "invalid_channel",
+            Self::InvalidObject { .. } => "invalid_object",
+            Self::InvalidTimeSignature { .. } => "invalid_time_signature",
+            Self::OrphanedIf { .. } => "orphaned_if",
+            Self::InvalidRandom { .. } => "invalid_random",
+        }
+    }
+}
+
+impl From<&BmsParseError> for Diagnostic {
+    fn from(error: &BmsParseError) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            line: error.line(),
+            message: error.to_string(),
+            code: error.code(),
+        }
+    }
+}
+
+/// Decides whether an error stops compilation or
+/// just gets written down, depending on the ```BmsParseMode```
+pub(crate) struct Diagnostics {
+    mode: BmsParseMode,
+    list: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub(crate) fn new(mode: BmsParseMode) -> Self {
+        Diagnostics { mode, list: vec![] }
+    }
+
+    /// Returns the error back in strict mode, otherwise
+    /// records it so the caller can skip over whatever broke
+    pub(crate) fn recover(&mut self, error: BmsParseError) -> Result<(), BmsParseError> {
+        match self.mode {
+            BmsParseMode::Strict => Err(error),
+            BmsParseMode::Lenient => {
+                self.list.push(Diagnostic::from(&error));
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a diagnostic that is never an error
+    pub(crate) fn note(&mut self, diagnostic: Diagnostic) {
+        if self.mode == BmsParseMode::Lenient {
+            self.list.push(diagnostic);
+        }
+    }
+
+    pub(crate) fn into_list(self) -> Vec<Diagnostic> {
+        self.list
+    }
+}
+
+/// Where a span of ```line``` starting at byte ```start``` and
+/// ending at byte ```end``` is, in 1-based character columns
+pub(crate) fn columns_of(line: &str, start: usize, end: usize) -> Range<usize> {
+    let start_column = line[..start].chars().count() + 1;
+    start_column..start_column + line[start..end].chars().count()
+}
+
+/// Every header of a chart by name, ignoring case
+pub type BmsHeaders = HashMap<UniCase<String>, String>;
+
 #[derive(Debug)]
 pub struct BmsChart {
-    pub headers: HashMap<UniCase<String>, String>,
+    pub headers: BmsHeaders,
+    /// Every header in the order they were compiled, including the
+    /// ones a later header with the same name replaced in ```headers```
+    pub header_lines: Vec<(UniCase<String>, String)>,
+    /// Headers like ```#WAVxx``` by their kind and decoded id.
+    ///
+    /// Unlike ```headers``` these tell ```#WAVaa``` and ```#WAVAA```
+    /// apart when the chart is ```#BASE 62```.
+    pub id_headers: HashMap<(BmsIdKind, u16), String>,
+    /// How the object values and ids of the chart are written
+    pub base: BmsBase,
     pub objects: Vec<BmsObject>,
     pub time_signatures: HashMap<u16, f64>,
+    /// Every value rolled by a ```#RANDOM``` or ```#SWITCH```
+    /// while compiling, in order. Feed them to a ```ReplayRng```
+    /// to compile the same variant again.
+    pub rolls: Vec<BmsRoll>,
+    /// The encoding the chart was decoded from, if it
+    /// was compiled from bytes
+    pub encoding: Option<BmsEncoding>,
 }
 
 // TODO: Clean up
 impl BmsChart {
     /// Updates/fixes the objects in the chart and ensures
     /// a good state for the ```objects``` property
-    /// 
-    /// Although sometimes unecessary, this function 
-    /// should be called right after you finish messing 
+    ///
+    /// Although sometimes unecessary, this function
+    /// should be called right after you finish messing
     /// around with the objects.
-    /// 
+    ///
     /// Nothing really break if you don't, but objects
     /// shouldn't have the same time unless their
     /// channel is BGM (01)
@@ -64,187 +352,142 @@ impl BmsChart {
 
 
     /// Compiles a ```BmsChart``` from a ```&str```.
-    /// 
-    /// The **inclusive** range of values returned by the rng function
+    ///
+    /// Both ```#RANDOM```/```#IF```/```#ELSEIF```/```#ELSE``` and
+    /// ```#SWITCH```/```#CASE``` blocks are resolved here, using
+    /// ```rng``` to roll their values (```#SETRANDOM``` and
+    /// ```#SETSWITCH``` don't roll).
+    ///
+    /// The **inclusive** range of values returned by the rng
     /// should be between 1 and ```max_value``` (AKA ```1..=max_value```)
-    /// 
+    ///
     /// If you can't use a random number generator for whatever reason,
     /// then a simple function like this would work as a placeholder:
-    /// 
+    ///
     /// ```rust
     /// fn bms_rng(max: u32) -> u32 {
     ///     max
     /// }
     /// ```
-    pub fn compile(data: &str, rng: fn(max_value: u32) -> u32) -> Option<BmsChart> {
-        // Anything that's related to the flow of the chart
-        #[derive(EnumIter, FromRepr, Debug)]
-        enum BmsControlMatches {
-            Random,
-            EndRandom,
-            If,
-            EndIf,
-        }
+    ///
+    /// Use a ```SeededRng``` to always get the same variant for a seed:
+    ///
+    /// ```rust
+    /// use bms_parser::{chart::BmsChart, rng::SeededRng};
+    ///
+    /// let data = "#RANDOM 2\n#IF 1\n#TITLE One\n#ELSE\n#TITLE Two\n#ENDIF";
+    /// let chart = BmsChart::compile(data, &mut SeededRng::new(42)).unwrap();
+    /// assert_eq!(chart.rolls.len(), 1);
+    /// ```
+    ///
+    /// Returns a ```BmsParseError``` describing the first line that
+    /// couldn't be compiled. Use ```compile_lenient``` to skip over
+    /// broken lines instead.
+    pub fn compile<R: BmsRng + ?Sized>(
+        data: &str,
+        rng: &mut R,
+    ) -> Result<BmsChart, BmsParseError> {
+        Self::compile_with_mode(data, rng, BmsParseMode::Strict).map(|(chart, _)| chart)
+    }
 
-        // Anything that's related to the chart or it's metadata
-        #[derive(EnumIter, FromRepr, Debug)]
-        enum BmsChartMatches {
-            TimeSignature,
-            Channel,
-            Header,
-        }
+    /// Compiles a ```BmsChart``` from the raw bytes of a file like
+    /// ```compile```.
+    ///
+    /// The encoding (UTF-8, Shift-JIS or EUC-KR) is detected unless
+    /// ```encoding``` is given, and is kept in ```encoding```.
+    pub fn compile_bytes<R: BmsRng + ?Sized>(
+        data: &[u8],
+        rng: &mut R,
+        encoding: Option<BmsEncoding>,
+    ) -> Result<BmsChart, BmsParseError> {
+        let (text, encoding) = decode_chart(data, encoding);
+        let mut chart = Self::compile(&text, rng)?;
+        chart.encoding = Some(encoding);
+        Ok(chart)
+    }
 
-        impl BmsControlMatches {
-            fn as_regex_str(&self) -> &'static str {
-                match self {
-                    Self::Random => r"^#RANDOM\s+(\d+)$",
-                    Self::EndRandom => r"^#ENDRANDOM$",
-                    Self::If => r"^#IF\s+(\d+)$",
-                    Self::EndIf => r"^#ENDIF$",
-                }
-            }
-        }
+    /// Compiles a ```BmsChart``` like ```compile```, reading it line
+    /// by line from ```reader``` instead of needing all of it at once.
+    ///
+    /// Every ```#RANDOM``` is rolled as soon as it's read and the
+    /// branches that aren't taken are skipped, so only what ends up
+    /// in the chart is kept in memory. The rolls are the same as
+    /// ```compile```'s, in the same order.
+    ///
+    /// See ```BmsTemplate::parse_reader``` for how the encoding is
+    /// found when ```encoding``` isn't given.
+    pub fn compile_reader<B: BufRead, R: BmsRng + ?Sized>(
+        reader: B,
+        rng: &mut R,
+        encoding: Option<BmsEncoding>,
+    ) -> Result<BmsChart, BmsReadError> {
+        let (chart, _) = template::compile_reader(reader, rng, BmsParseMode::Strict, encoding)?;
+        Ok(chart)
+    }
 
-        impl BmsChartMatches {
-            fn as_regex_str(&self) -> &'static str {
-                match self {
-                    Self::TimeSignature => r"^#(\d\d\d)02:(\S*)$",
-                    Self::Channel => r"^#(?:EXT\s+#)?(\d\d\d)(\S\S):([0-9a-zA-Z]*)$",
-                    Self::Header => r"^#(\w+)(?:\s+(\S.*))?$",
-                }
-            }
+    /// Compiles a ```BmsChart``` from a ```&str``` like ```compile```,
+    /// but skips any line or object that can't be compiled instead
+    /// of failing.
+    ///
+    /// Everything that was skipped is reported in the returned
+    /// ```Vec<Diagnostic>```.
+    pub fn compile_lenient<R: BmsRng + ?Sized>(
+        data: &str,
+        rng: &mut R,
+    ) -> (BmsChart, Vec<Diagnostic>) {
+        match Self::compile_with_mode(data, rng, BmsParseMode::Lenient) {
+            Ok(v) => v,
+            Err(_) => unreachable!("lenient compilation never fails"),
         }
+    }
 
-        let mut chart = BmsChart {
-            headers: HashMap::new(),
-            objects: vec![],
-            time_signatures: HashMap::new(),
-        };
-
-        let mut rng_stack = vec![];
-        let mut skip_stack = vec![];
-
-        let control_regex_expressions = BmsControlMatches::iter().map(|v| v.as_regex_str());
-        let control_regexes: Vec<Regex> = control_regex_expressions
-            .map(|expr| Regex::new(expr).unwrap())
-            .collect();
-        let chart_regex_expressions = BmsChartMatches::iter().map(|v| v.as_regex_str());
-        let chart_regexes: Vec<Regex> = chart_regex_expressions
-            .map(|expr| Regex::new(expr).unwrap())
-            .collect();
-        for line in data.trim().lines() {
-            // if line.starts_with('#') == false {
-            //     continue;
-            // }
-            let mut matched_any = false;
-            for i in 0..control_regexes.len() {
-                let v = &control_regexes[i];
-                let captures = match v.captures(line) {
-                    Some(v) => v,
-                    None => continue,
-                };
-
-                let match_type = BmsControlMatches::from_repr(i).unwrap();
-
-                matched_any = true;
-
-                match match_type {
-                    BmsControlMatches::Random => {
-                        let max = match u32::from_str_radix(&captures[1], 10) {
-                            Ok(v) => v,
-                            Err(_) => return None,
-                        };
-                        let rng_value = rng(max);
-                        rng_stack.push(rng_value);
-                    }
-                    BmsControlMatches::EndRandom => {
-                        rng_stack.pop();
-                    }
-                    BmsControlMatches::If => {
-                        let value = match u32::from_str_radix(&captures[1], 10) {
-                            Ok(v) => v,
-                            Err(_) => return None,
-                        };
-                        let rng_value = match rng_stack.last() {
-                            Some(v) => *v,
-                            None => return None,
-                        };
-                        skip_stack.push(rng_value != value);
-                    }
-                    BmsControlMatches::EndIf => {
-                        skip_stack.pop();
-                    }
-                }
-            }
+    /// Compiles a ```BmsChart``` from a ```&str```, reacting to broken
+    /// lines as ```mode``` says.
+    ///
+    /// The returned ```Vec<Diagnostic>``` is always empty in strict mode.
+    ///
+    /// This is the same as parsing a ```BmsTemplate``` and instantiating
+    /// it right away, so use that directly to compile many variants.
+    pub fn compile_with_mode<R: BmsRng + ?Sized>(
+        data: &str,
+        rng: &mut R,
+        mode: BmsParseMode,
+    ) -> Result<(BmsChart, Vec<Diagnostic>), BmsParseError> {
+        let (template, diagnostics) = BmsTemplate::parse_with_mode(data, mode)?;
+        Ok((template.instantiate(rng), diagnostics))
+    }
 
-            let skipping = *skip_stack.last().unwrap_or(&false);
-
-            if skipping == false && matched_any == false {
-                for i in 0..chart_regexes.len() {
-                    let v = &chart_regexes[i];
-                    let captures = match v.captures(line) {
-                        Some(v) => v,
-                        None => continue,
-                    };
-
-                    let match_type = BmsChartMatches::from_repr(i).unwrap();
-
-                    match match_type {
-                        BmsChartMatches::TimeSignature => {
-                            let measure = match u16::from_str_radix(&captures[1], 10) {
-                                Ok(v) => v,
-                                Err(_) => return None,
-                            };
-                            let time_signature: f64 = match captures[2].parse() {
-                                Ok(v) => v,
-                                Err(_) => return None,
-                            };
-                            chart.time_signatures.insert(measure, time_signature);
-                        }
-                        BmsChartMatches::Channel => {
-                            let measure = match u16::from_str_radix(&captures[1], 10) {
-                                Ok(v) => v,
-                                Err(_) => return None,
-                            };
-                            let channel = match u16::from_str_radix(&captures[2], 36) {
-                                Ok(v) => v,
-                                Err(_) => return None,
-                            };
-                            let values_str = &captures[3];
-                            // Values come in pairs so we divide by 2 to get the divisions in the measurew
-                            let divisions = values_str.len() / 2;
-                            for i in 0..divisions {
-                                let text = &values_str[i * 2..=i * 2 + 1];
-                                let value = match u16::from_str_radix(text, 36) {
-                                    Ok(v) => v,
-                                    Err(_) => return None,
-                                };
-                                if value != 0 {
-                                    let object = BmsObject {
-                                        channel,
-                                        time: BmsTime {
-                                            measure,
-                                            fraction: OrderedFloat(
-                                                (1.0 / divisions as f64) * i as f64,
-                                            ),
-                                        },
-                                        value,
-                                    };
-                                    chart.objects.push(object);
-                                }
-                            }
-                        }
-                        BmsChartMatches::Header => {
-                            let name = &captures[1];
-                            let value = &captures[2];
-                            chart.headers.insert(UniCase::new(name.to_string()), value.to_string());
-                        }
-                    }
-                    break;
-                }
-            }
-        }
-        chart.update_objects();
-        Some(chart)
+    /// Reads only the headers of a chart, like ```#TITLE```,
+    /// ```#ARTIST``` or ```#PLAYLEVEL```, without decoding any of
+    /// its objects. Meant for building song lists quickly.
+    ///
+    /// Headers inside ```#RANDOM``` and ```#SWITCH``` blocks are
+    /// resolved with ```rng``` the same way ```compile``` does, so
+    /// the same rolls give the same ```headers``` as compiling.
+    ///
+    /// ```rust
+    /// use bms_parser::chart::BmsChart;
+    /// use unicase::UniCase;
+    ///
+    /// let data = "#TITLE Song\n#00111:01\n#RANDOM 2\n#IF 2\n#PLAYLEVEL 7\n#ENDIF\n#ENDRANDOM";
+    /// let headers = BmsChart::scan_headers(data, &mut |_| 2).unwrap();
+    /// assert_eq!(headers[&UniCase::new("PLAYLEVEL".to_string())], "7");
+    /// ```
+    pub fn scan_headers<R: BmsRng + ?Sized>(
+        data: &str,
+        rng: &mut R,
+    ) -> Result<BmsHeaders, BmsParseError> {
+        Self::scan_headers_with_mode(data, rng, BmsParseMode::Strict).map(|(headers, _)| headers)
+    }
+
+    /// Reads only the headers of a chart like ```scan_headers```,
+    /// reacting to broken control flow lines as ```mode``` says.
+    pub fn scan_headers_with_mode<R: BmsRng + ?Sized>(
+        data: &str,
+        rng: &mut R,
+        mode: BmsParseMode,
+    ) -> Result<(BmsHeaders, Vec<Diagnostic>), BmsParseError> {
+        let (template, diagnostics) = BmsTemplate::parse_headers_with_mode(data, mode)?;
+        Ok((template.instantiate(rng).headers, diagnostics))
     }
 }
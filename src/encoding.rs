@@ -0,0 +1,167 @@
+use std::borrow::Cow;
+
+use encoding_rs::{Encoding, EUC_KR, SHIFT_JIS, UTF_8};
+
+/// The text encodings BMS files are usually found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsEncoding {
+    Utf8,
+    /// What nearly every Japanese chart uses (as Windows-31J)
+    ShiftJis,
+    /// What most Korean charts use (as Windows-949)
+    EucKr,
+}
+
+impl BmsEncoding {
+    fn as_encoding(&self) -> &'static Encoding {
+        match self {
+            BmsEncoding::Utf8 => UTF_8,
+            BmsEncoding::ShiftJis => SHIFT_JIS,
+            BmsEncoding::EucKr => EUC_KR,
+        }
+    }
+
+    /// Guesses the encoding of a chart.
+    ///
+    /// A UTF-8 BOM or valid UTF-8 means UTF-8. Otherwise, whichever of
+    /// Shift-JIS and EUC-KR decodes without errors and into more
+    /// sensible text wins, with Shift-JIS winning ties.
+    pub fn detect(data: &[u8]) -> BmsEncoding {
+        if data.starts_with(b"\xEF\xBB\xBF") || std::str::from_utf8(data).is_ok() {
+            return BmsEncoding::Utf8;
+        }
+
+        let (shift_jis, _, shift_jis_errors) = SHIFT_JIS.decode(data);
+        let (euc_kr, _, euc_kr_errors) = EUC_KR.decode(data);
+        match (shift_jis_errors, euc_kr_errors) {
+            (false, true) => BmsEncoding::ShiftJis,
+            (true, false) => BmsEncoding::EucKr,
+            // Korean decoded as Shift-JIS usually turns into a lot of
+            // half-width katakana, which barely anyone uses on purpose
+            _ => {
+                if korean_score(&euc_kr) > japanese_score(&shift_jis) {
+                    BmsEncoding::EucKr
+                } else {
+                    BmsEncoding::ShiftJis
+                }
+            }
+        }
+    }
+
+    /// Decodes ```data``` as this encoding, without a BOM.
+    ///
+    /// Anything that can't be decoded becomes U+FFFD.
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Cow<'a, str> {
+        let data = match self {
+            BmsEncoding::Utf8 => data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data),
+            _ => data,
+        };
+        self.as_encoding().decode_without_bom_handling(data).0
+    }
+}
+
+fn japanese_score(text: &str) -> isize {
+    text.chars()
+        .map(|c| match c {
+            // Hiragana, katakana and kanji
+            '\u{3040}'..='\u{30FF}' | '\u{4E00}'..='\u{9FFF}' => 1,
+            // CJK punctuation (like 【】 and 「」), full-width letters
+            // and digits, and the symbols Shift-JIS has (★, ♪, ※...)
+            '\u{3000}'..='\u{303F}'
+            | '\u{FF01}'..='\u{FF5E}'
+            | '\u{2010}'..='\u{203F}'
+            | '\u{2190}'..='\u{21FF}'
+            | '\u{2200}'..='\u{22FF}'
+            | '\u{2500}'..='\u{25FF}'
+            | '\u{2600}'..='\u{26FF}' => 1,
+            // Half-width katakana
+            '\u{FF61}'..='\u{FF9F}' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Nearly every Shift-JIS pair is also a Windows-949 one, but they
+/// decode into Hangul outside of the 2350 common syllables EUC-KR
+/// (KS X 1001) has, which real Korean text barely ever uses
+fn korean_score(text: &str) -> isize {
+    text.chars()
+        .map(|c| match c {
+            '\u{AC00}'..='\u{D7A3}' if is_common_hangul(c) => 1,
+            '\u{AC00}'..='\u{D7A3}' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Whether a Hangul syllable is one of KS X 1001's, which are the
+/// ones both bytes of are 0xA1 or above in Windows-949
+fn is_common_hangul(c: char) -> bool {
+    let mut buffer = [0; 4];
+    let (bytes, _, _) = EUC_KR.encode(c.encode_utf8(&mut buffer));
+    bytes.iter().all(|v| *v >= 0xA1)
+}
+
+/// Decodes the bytes of a chart into text that can be compiled,
+/// detecting the encoding unless ```encoding``` is given.
+///
+/// CRLF and stray CR line endings become LF, so line
+/// numbers in errors match what text editors show.
+pub fn decode_chart(data: &[u8], encoding: Option<BmsEncoding>) -> (Cow<'_, str>, BmsEncoding) {
+    let encoding = encoding.unwrap_or_else(|| BmsEncoding::detect(data));
+    let text = encoding.decode(data);
+    let text = if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        text
+    };
+    (text, encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_jis_symbols_are_not_mistaken_for_korean() {
+        for title in [
+            "#TITLE ★テスト★",
+            "#TITLE 【ＢＭＳ】曲名",
+            "#ARTIST ～ｘｘ～ feat. 初音",
+        ] {
+            let (bytes, _, _) = SHIFT_JIS.encode(title);
+            assert_eq!(
+                BmsEncoding::detect(&bytes),
+                BmsEncoding::ShiftJis,
+                "{}",
+                title
+            );
+            assert_eq!(BmsEncoding::ShiftJis.decode(&bytes), title);
+        }
+    }
+
+    #[test]
+    fn korean_is_detected() {
+        for title in [
+            "#TITLE 안녕하세요",
+            "#ARTIST 작곡가 (feat. 보컬)",
+            "#TITLE ★별빛★",
+        ] {
+            let (bytes, _, _) = EUC_KR.encode(title);
+            assert_eq!(BmsEncoding::detect(&bytes), BmsEncoding::EucKr, "{}", title);
+            assert_eq!(BmsEncoding::EucKr.decode(&bytes), title);
+        }
+    }
+
+    #[test]
+    fn utf8_and_bom_are_detected() {
+        assert_eq!(
+            BmsEncoding::detect("#TITLE テスト".as_bytes()),
+            BmsEncoding::Utf8
+        );
+        let data = b"\xEF\xBB\xBF#TITLE a\r\n#ARTIST b\r#GENRE c";
+        let (text, encoding) = decode_chart(data, None);
+        assert_eq!(encoding, BmsEncoding::Utf8);
+        assert_eq!(text, "#TITLE a\n#ARTIST b\n#GENRE c");
+    }
+}
@@ -0,0 +1,156 @@
+use super::chart::BmsChart;
+use super::notes::{BmsNote, BmsNoteType};
+use super::timing::{BmsTime, BmsTiming, TimingMap};
+
+/// Which of the BGA layers an image is shown on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BmsBgaLayer {
+    /// 04, the bottom layer
+    Base,
+    /// 06, shown for a moment after a POOR
+    Poor,
+    /// 07, drawn over ```Base```
+    Layer,
+    /// 0A, drawn over ```Layer```
+    Layer2,
+}
+
+impl BmsBgaLayer {
+    /// The layer of a BGA channel, or ```None``` if it isn't one
+    pub fn from_channel(channel: u16) -> Option<Self> {
+        match channel {
+            4 => Some(BmsBgaLayer::Base),
+            6 => Some(BmsBgaLayer::Poor),
+            7 => Some(BmsBgaLayer::Layer),
+            10 => Some(BmsBgaLayer::Layer2),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happens while a chart plays
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChartEvent<'a> {
+    /// The line at the start of a measure
+    MeasureLine { measure: u16 },
+    /// The length of the measure starting here, 1 being 4/4
+    TimeSignature { ratio: f64 },
+    BpmChange { bpm: f64 },
+    ScrollChange { scroll: f64 },
+    BgaChange { layer: BmsBgaLayer, bmp: u16 },
+    Bgm { keysound: u16 },
+    /// Any note that isn't BGM
+    Note(&'a BmsNote),
+    /// The chart stops for ```length``` 192nds of a 4/4 measure
+    Stop { length: f64 },
+}
+
+impl ChartEvent<'_> {
+    /// Where the event goes among events at the same time: whatever
+    /// changes how the chart moves goes before what's played, and
+    /// stops go last since they start once everything else happened
+    fn order(&self) -> u8 {
+        match self {
+            ChartEvent::MeasureLine { .. } => 0,
+            ChartEvent::TimeSignature { .. } => 1,
+            ChartEvent::BpmChange { .. } => 2,
+            ChartEvent::ScrollChange { .. } => 3,
+            ChartEvent::BgaChange { .. } => 4,
+            ChartEvent::Bgm { .. } => 5,
+            ChartEvent::Note(_) => 6,
+            ChartEvent::Stop { .. } => 7,
+        }
+    }
+}
+
+/// A ```ChartEvent``` and when it happens
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmsTimedEvent<'a> {
+    pub time: BmsTime,
+    /// Seconds after the chart starts
+    pub seconds: f64,
+    pub event: ChartEvent<'a>,
+}
+
+/// Generates every event of a chart in the order they happen, out of
+/// its objects, its ```BmsTiming``` and its notes (as
+/// ```generate_notes``` gives them).
+///
+/// Events at the same time go in the order of ```ChartEvent```'s
+/// variants (so a BPM change comes before a note), and then in the
+/// order they're in the chart or in ```notes```.
+pub fn generate_events<'a>(
+    chart: &BmsChart,
+    notes: &'a [BmsNote],
+    timing: &BmsTiming,
+) -> impl Iterator<Item = BmsTimedEvent<'a>> {
+    let mut events: Vec<(BmsTime, ChartEvent<'a>)> = vec![];
+    let timed = |(time, value): (&BmsTime, &f64)| (*time, *value);
+    events.extend(
+        timing
+            .bpm_changes
+            .iter()
+            .map(timed)
+            .map(|(time, bpm)| (time, ChartEvent::BpmChange { bpm })),
+    );
+    events.extend(
+        timing
+            .scroll_changes
+            .iter()
+            .map(timed)
+            .map(|(time, scroll)| (time, ChartEvent::ScrollChange { scroll })),
+    );
+    events.extend(
+        timing
+            .stops
+            .iter()
+            .map(timed)
+            .map(|(time, length)| (time, ChartEvent::Stop { length })),
+    );
+    events.extend(chart.time_signatures.iter().map(|(measure, ratio)| {
+        (
+            BmsTime::new(*measure, 0.0),
+            ChartEvent::TimeSignature { ratio: *ratio },
+        )
+    }));
+    events.extend(chart.objects.iter().filter_map(|object| {
+        let layer = BmsBgaLayer::from_channel(object.channel)?;
+        Some((
+            object.time,
+            ChartEvent::BgaChange {
+                layer,
+                bmp: object.value,
+            },
+        ))
+    }));
+    events.extend(notes.iter().map(|note| match note.note_type {
+        BmsNoteType::BGM { keysound } => (note.hit_time, ChartEvent::Bgm { keysound }),
+        _ => (note.hit_time, ChartEvent::Note(note)),
+    }));
+
+    // Every measure gets a line, up to the last one with anything in it
+    if let Some(last) = events.iter().map(|(time, _)| time.measure).max() {
+        events.extend((0..=last).map(|measure| {
+            (
+                BmsTime::new(measure, 0.0),
+                ChartEvent::MeasureLine { measure },
+            )
+        }));
+    }
+
+    // Stable, and what comes from a HashMap is the only one of its
+    // kind at its time, so the order doesn't depend on any hashing
+    events.sort_by_key(|(time, event)| (*time, event.order()));
+
+    // The events are sorted, so their seconds are found in one pass
+    let times: Vec<BmsTime> = events.iter().map(|(time, _)| *time).collect();
+    let seconds = TimingMap::new(timing, &chart.time_signatures).into_seconds_sorted(times);
+    events
+        .into_iter()
+        .zip(seconds)
+        .map(|((time, event), seconds)| BmsTimedEvent {
+            time,
+            seconds,
+            event,
+        })
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+
+use crate::{
+    chart::BmsChart,
+    metadata::{generate_metadata, BmsPlayer},
+};
+
+/// A side of the play field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsSide {
+    /// 1P, channels 1x/3x/5x/Dx
+    Player1,
+    /// 2P, channels 2x/4x/6x/Ex
+    Player2,
+}
+
+/// What the objects of a note channel are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsNoteChannel {
+    /// 1x/2x
+    Visible,
+    /// 3x/4x
+    Invisible,
+    /// 5x/6x
+    Long,
+    /// Dx/Ex
+    Mine,
+}
+
+/// Splits a note channel like ```16``` or ```D2``` into its kind,
+/// its side and its key (the second digit, 6 and 2 here)
+pub fn split_note_channel(channel: u16) -> Option<(BmsNoteChannel, BmsSide, u16)> {
+    let (kind, side) = match channel / 36 {
+        1 => (BmsNoteChannel::Visible, BmsSide::Player1),
+        2 => (BmsNoteChannel::Visible, BmsSide::Player2),
+        3 => (BmsNoteChannel::Invisible, BmsSide::Player1),
+        4 => (BmsNoteChannel::Invisible, BmsSide::Player2),
+        5 => (BmsNoteChannel::Long, BmsSide::Player1),
+        6 => (BmsNoteChannel::Long, BmsSide::Player2),
+        // D and E
+        13 => (BmsNoteChannel::Mine, BmsSide::Player1),
+        14 => (BmsNoteChannel::Mine, BmsSide::Player2),
+        _ => return None,
+    };
+    match channel % 36 {
+        0 => None,
+        key => Some((kind, side, key)),
+    }
+}
+
+/// A physical lane of the play field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BmsLane {
+    /// 0-based position from the left of the play field
+    pub index: u16,
+    pub scratch: bool,
+    pub side: BmsSide,
+}
+
+/// How many keys a chart is played with, which decides the lane
+/// every note channel goes to.
+///
+/// Lanes go from left to right: 1P's scratch is the leftmost lane,
+/// and 2P's the rightmost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyMode {
+    /// 1P's 11-15 and scratch 16
+    Key5,
+    /// ```Key5``` plus keys 6 and 7 on 18 and 19
+    Key7,
+    /// The 9 buttons of a PMS chart, 11-15 then 22-25. There's no
+    /// scratch and every button is on 1P's side.
+    Key9,
+    /// ```Key5``` on both sides
+    Key10,
+    /// ```Key7``` on both sides
+    Key14,
+    /// A keyboard chart on 1P's extended channels: 24 keys on
+    /// 11-19 and 1A-1O, then the wheel (up and down) on 1P and 1Q,
+    /// which count as scratches
+    Key24,
+    /// ```Key24``` on both sides
+    Key48,
+}
+
+impl KeyMode {
+    /// The number of lanes, scratches included
+    pub fn lane_count(&self) -> u16 {
+        match self {
+            KeyMode::Key5 => 6,
+            KeyMode::Key7 => 8,
+            KeyMode::Key9 => 9,
+            KeyMode::Key10 => 12,
+            KeyMode::Key14 => 16,
+            KeyMode::Key24 => 26,
+            KeyMode::Key48 => 52,
+        }
+    }
+
+    /// Whether the mode uses both sides of the play field
+    pub fn is_double(&self) -> bool {
+        matches!(self, KeyMode::Key10 | KeyMode::Key14 | KeyMode::Key48)
+    }
+
+    /// The lane the objects of a note channel go to, or ```None```
+    /// if the channel isn't a note channel of this mode
+    pub fn lane(&self, channel: u16) -> Option<BmsLane> {
+        let (_, side, key) = split_note_channel(channel)?;
+        self.key_lane(side, key)
+    }
+
+    /// The lane of ```key``` (the second digit of a note channel)
+    /// on ```side```
+    pub fn key_lane(&self, side: BmsSide, key: u16) -> Option<BmsLane> {
+        match self {
+            KeyMode::Key5 | KeyMode::Key7 | KeyMode::Key10 | KeyMode::Key14 => {
+                self.beat_lane(side, key)
+            }
+            KeyMode::Key9 => {
+                let index = match (side, key) {
+                    (BmsSide::Player1, 1..=5) => key - 1,
+                    (BmsSide::Player2, 2..=5) => key + 3,
+                    _ => return None,
+                };
+                Some(BmsLane {
+                    index,
+                    scratch: false,
+                    side: BmsSide::Player1,
+                })
+            }
+            KeyMode::Key24 | KeyMode::Key48 => {
+                // 1-9 then A-Q, in base 36
+                if !(1..=26).contains(&key) {
+                    return None;
+                }
+                let index = match side {
+                    BmsSide::Player1 => key - 1,
+                    BmsSide::Player2 if self.is_double() => key + 25,
+                    BmsSide::Player2 => return None,
+                };
+                Some(BmsLane {
+                    index,
+                    scratch: key > 24,
+                    side,
+                })
+            }
+        }
+    }
+
+    /// ```key_lane``` for the beatmania layouts (5, 7, 10 and 14 keys)
+    fn beat_lane(&self, side: BmsSide, key: u16) -> Option<BmsLane> {
+        let seven = matches!(self, KeyMode::Key7 | KeyMode::Key14);
+        // Where the key is among the keys of its side, scratch being 0
+        let position = match key {
+            1..=5 => key,
+            6 => 0,
+            8 | 9 if seven => key - 2,
+            _ => return None,
+        };
+        let keys = if seven { 7 } else { 5 };
+        let index = match side {
+            BmsSide::Player1 => position,
+            BmsSide::Player2 if self.is_double() => match position {
+                0 => keys * 2 + 1,
+                _ => keys + position,
+            },
+            BmsSide::Player2 => return None,
+        };
+        Some(BmsLane {
+            index,
+            scratch: position == 0,
+            side,
+        })
+    }
+
+    /// Guesses the key mode of a chart from the extension of its
+    /// file (like ```pms``` or ```.bme```), its ```#PLAYER``` and
+    /// the note channels that have objects.
+    ///
+    /// A ```#PLAYER``` of 2 or 3 makes it a double play mode even
+    /// if only 1P has notes. The extension is only trusted for PMS
+    /// and 7 key charts, and never over channels the mode doesn't
+    /// have (like a scratch in a ```.pms```).
+    pub fn detect(chart: &BmsChart, extension: Option<&str>) -> KeyMode {
+        guess_key_mode(chart, extension).key_mode
+    }
+}
+
+impl fmt::Display for KeyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys = match self {
+            KeyMode::Key5 => 5,
+            KeyMode::Key7 => 7,
+            KeyMode::Key9 => 9,
+            KeyMode::Key10 => 10,
+            KeyMode::Key14 => 14,
+            KeyMode::Key24 => 24,
+            KeyMode::Key48 => 48,
+        };
+        write!(f, "{}K", keys)
+    }
+}
+
+/// A key mode guessed from a chart, along with why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BmsKeyModeGuess {
+    pub key_mode: KeyMode,
+    /// What the guess is based on, like ```uses 18/19 → 7K```
+    pub reason: String,
+}
+
+impl BmsChart {
+    /// Guesses the key mode of the chart from which note channels
+    /// (visible, invisible, long and mine) have objects, and its
+    /// ```#PLAYER```.
+    ///
+    /// Unlike ```KeyMode::detect``` there's no file extension to go
+    /// by, so a PMS chart is only told apart from a double play one
+    /// by it using 22-25 without the 2P scratch or 21.
+    pub fn infer_key_mode(&self) -> BmsKeyModeGuess {
+        guess_key_mode(self, None)
+    }
+}
+
+/// Guesses the key mode of ```chart```, see ```KeyMode::detect```
+fn guess_key_mode(chart: &BmsChart, extension: Option<&str>) -> BmsKeyModeGuess {
+    let extension = extension
+        .map(|v| v.trim_start_matches('.').to_ascii_lowercase())
+        .unwrap_or_default();
+    let player = generate_metadata(chart).player.and_then(|v| v.value);
+    let player_double = matches!(player, Some(BmsPlayer::Couple | BmsPlayer::Double));
+
+    // Which keys of each side have objects
+    let mut used = [[false; 36]; 2];
+    for object in &chart.objects {
+        if let Some((_, side, key)) = split_note_channel(object.channel) {
+            used[side as usize][key as usize] = true;
+        }
+    }
+    let uses = |side: BmsSide, keys: &[u16]| keys.iter().any(|v| used[side as usize][*v as usize]);
+    let guess = |key_mode: KeyMode, reasons: Vec<&str>| BmsKeyModeGuess {
+        key_mode,
+        reason: format!("{} → {}", reasons.join(", "), key_mode),
+    };
+
+    let mut double_reasons = vec![];
+    if used[BmsSide::Player2 as usize].contains(&true) {
+        double_reasons.push("uses 2P side");
+    }
+    if player_double {
+        double_reasons.push("#PLAYER is double play");
+    }
+
+    let extended: Vec<u16> = (10..36).collect();
+    if uses(BmsSide::Player1, &extended) || uses(BmsSide::Player2, &extended) {
+        let mut reasons = vec!["uses keys past 9 (xA-xQ)"];
+        if double_reasons.is_empty() {
+            return guess(KeyMode::Key24, reasons);
+        }
+        reasons.extend(double_reasons);
+        return guess(KeyMode::Key48, reasons);
+    }
+
+    let beat_only = uses(BmsSide::Player1, &[6, 8, 9]) || uses(BmsSide::Player2, &[1, 6, 8, 9]);
+    if extension == "pms" && !beat_only {
+        return guess(KeyMode::Key9, vec!["extension is .pms"]);
+    }
+    // A PMS chart played as BMS looks like 1P plus a few 2P keys,
+    // without a #PLAYER saying it's a double play chart
+    if uses(BmsSide::Player2, &[2, 3, 4, 5]) && !beat_only && !player_double {
+        return guess(KeyMode::Key9, vec!["uses 22-25 without 21 or a scratch"]);
+    }
+
+    let mut seven_reasons = vec![];
+    if uses(BmsSide::Player1, &[8, 9]) {
+        seven_reasons.push("uses 18/19");
+    }
+    if uses(BmsSide::Player2, &[8, 9]) {
+        seven_reasons.push("uses 28/29");
+    }
+    if extension == "bme" || extension == "bml" {
+        seven_reasons.push("extension is .bme/.bml");
+    }
+    let double = !double_reasons.is_empty();
+    let seven = !seven_reasons.is_empty();
+    let mut reasons = double_reasons;
+    reasons.extend(seven_reasons);
+    if reasons.is_empty() {
+        reasons.push("only uses 1P keys 1-5 and scratch");
+    }
+    let key_mode = match (double, seven) {
+        (false, false) => KeyMode::Key5,
+        (false, true) => KeyMode::Key7,
+        (true, false) => KeyMode::Key10,
+        (true, true) => KeyMode::Key14,
+    };
+    guess(key_mode, reasons)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::ReplayRng;
+
+    fn infer(data: &str) -> KeyMode {
+        let chart = BmsChart::compile(data, &mut ReplayRng::from_values(vec![])).unwrap();
+        chart.infer_key_mode().key_mode
+    }
+
+    #[test]
+    fn player_is_parsed_like_metadata() {
+        assert_eq!(infer("#PLAYER 3.0\n#00111:01\n#00118:01"), KeyMode::Key14);
+        assert_eq!(infer("#PLAYER 1\n#00111:01\n#00118:01"), KeyMode::Key7);
+    }
+}
@@ -1,23 +1,99 @@
 use std::collections::HashMap;
 
-use regex::Regex;
-
 use super::chart::BmsChart;
+use super::lexer::BmsIdKind;
+use super::notes::{BmsNote, BmsNoteType};
+use super::timing::{BmsTime, BmsTiming, TimingMap};
 
 pub fn generate_keysounds(chart: &BmsChart) -> HashMap<u16, String> {
-    let mut keysounds = HashMap::new();
-    let keysound_regex = Regex::new(r"^wav(\S\S)$").unwrap();
-    for key in chart.headers.keys() {
-        let lowercase_key = key.to_lowercase();
-        let captures = match keysound_regex.captures(&lowercase_key) {
-            Some(v) => v,
-            None => continue,
-        };
-        let id = match u16::from_str_radix(&captures[1], 36) {
-            Ok(v) => v,
-            Err(_) => continue,
+    chart
+        .id_headers
+        .iter()
+        .filter(|((kind, _), _)| *kind == BmsIdKind::Wav)
+        .map(|((_, id), value)| (*id, value.clone()))
+        .collect()
+}
+
+/// A point where the keysound of a lane changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BmsKeysoundChange {
+    pub time: BmsTime,
+    pub seconds: f64,
+    pub keysound: u16,
+}
+
+/// What every lane plays when it's pressed with no note to hit
+/// (AKA an empty POOR), over the course of a chart.
+///
+/// Every visible and hidden note changes the keysound of its lane
+/// to its own once it reaches the judge line. Before the first one,
+/// a lane plays the keysound of its first note.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BmsLaneKeysounds {
+    /// The changes of each lane by ```BmsNote::lane```, in order
+    pub lanes: HashMap<u16, Vec<BmsKeysoundChange>>,
+}
+
+impl BmsLaneKeysounds {
+    /// The keysound ```lane``` plays at ```time```, or ```None``` if
+    /// it has no notes
+    pub fn keysound_at(&self, lane: u16, time: BmsTime) -> Option<u16> {
+        let changes = self.lanes.get(&lane)?;
+        let index = changes.partition_point(|v| v.time <= time);
+        Some(changes[index.saturating_sub(1)].keysound)
+    }
+
+    /// ```keysound_at```, but ```seconds``` after the chart starts
+    pub fn keysound_at_seconds(&self, lane: u16, seconds: f64) -> Option<u16> {
+        let changes = self.lanes.get(&lane)?;
+        let index = changes.partition_point(|v| v.seconds <= seconds);
+        Some(changes[index.saturating_sub(1)].keysound)
+    }
+}
+
+/// Generates the ```BmsLaneKeysounds``` of the notes of a chart,
+/// as ```generate_notes``` gives them
+pub fn generate_lane_keysounds(
+    chart: &BmsChart,
+    notes: &[BmsNote],
+    timing: &BmsTiming,
+) -> BmsLaneKeysounds {
+    let mut lanes: HashMap<u16, Vec<(BmsTime, bool, u16)>> = HashMap::new();
+    for note in notes {
+        let (keysound, hidden) = match note.note_type {
+            BmsNoteType::Normal { keysound } | BmsNoteType::Long { keysound, .. } => {
+                (keysound, false)
+            }
+            BmsNoteType::Hidden { keysound } => (keysound, true),
+            // Landmines and BGM don't belong to the player
+            BmsNoteType::Mine { .. } | BmsNoteType::BGM { .. } => continue,
         };
-        keysounds.insert(id, chart.headers[key].clone());
+        lanes
+            .entry(note.lane)
+            .or_default()
+            .push((note.hit_time, hidden, keysound));
     }
-    keysounds
-}
\ No newline at end of file
+
+    let timing_map = TimingMap::new(timing, &chart.time_signatures);
+    let lanes = lanes
+        .into_iter()
+        .map(|(lane, mut changes)| {
+            // A hidden note can't take over from a note that's played
+            // at the same time, so those go first and the rest go
+            changes.sort_by_key(|(time, hidden, _)| (*time, *hidden));
+            changes.dedup_by_key(|(time, ..)| *time);
+            let seconds = timing_map.seconds_sorted(changes.iter().map(|(time, ..)| *time));
+            let changes = changes
+                .iter()
+                .zip(seconds)
+                .map(|((time, _, keysound), seconds)| BmsKeysoundChange {
+                    time: *time,
+                    seconds,
+                    keysound: *keysound,
+                })
+                .collect();
+            (lane, changes)
+        })
+        .collect();
+    BmsLaneKeysounds { lanes }
+}
@@ -0,0 +1,343 @@
+/// A control flow command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsControl {
+    Random,
+    SetRandom,
+    EndRandom,
+    If,
+    ElseIf,
+    Else,
+    EndIf,
+    Switch,
+    SetSwitch,
+    Case,
+    Skip,
+    Def,
+    EndSwitch,
+}
+
+impl BmsControl {
+    /// Every control command along with its name and whether it
+    /// takes a number after it
+    const ALL: [(BmsControl, &'static str, bool); 13] = [
+        (BmsControl::Random, "RANDOM", true),
+        (BmsControl::SetRandom, "SETRANDOM", true),
+        (BmsControl::EndRandom, "ENDRANDOM", false),
+        (BmsControl::If, "IF", true),
+        (BmsControl::ElseIf, "ELSEIF", true),
+        (BmsControl::Else, "ELSE", false),
+        (BmsControl::EndIf, "ENDIF", false),
+        (BmsControl::Switch, "SWITCH", true),
+        (BmsControl::SetSwitch, "SETSWITCH", true),
+        (BmsControl::Case, "CASE", true),
+        (BmsControl::Skip, "SKIP", false),
+        (BmsControl::Def, "DEF", false),
+        (BmsControl::EndSwitch, "ENDSW", false),
+    ];
+
+    /// The name of the control, like ```ENDIF```
+    pub fn name(&self) -> &'static str {
+        Self::ALL.iter().find(|(v, ..)| v == self).unwrap().1
+    }
+}
+
+/// A slice of a line along with the byte it starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BmsSpan<'a> {
+    pub text: &'a str,
+    /// Byte offset of ```text``` in the line
+    pub start: usize,
+}
+
+impl<'a> BmsSpan<'a> {
+    fn new(line: &'a str, start: usize, end: usize) -> Self {
+        BmsSpan {
+            text: &line[start..end],
+            start,
+        }
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+}
+
+/// What a line of a chart is, borrowing its parts from the line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmsToken<'a> {
+    /// ```#RANDOM 2```, ```#ENDIF```, etc.
+    ///
+    /// ```argument``` is only there for the commands that take one,
+    /// and can be empty or not a number.
+    Control {
+        control: BmsControl,
+        argument: Option<BmsSpan<'a>>,
+    },
+    /// ```#xxx02:value```
+    TimeSignature {
+        measure: BmsSpan<'a>,
+        value: BmsSpan<'a>,
+    },
+    /// ```#xxxCC:values```
+    Channel {
+        measure: BmsSpan<'a>,
+        channel: BmsSpan<'a>,
+        values: BmsSpan<'a>,
+    },
+    /// ```#NAME value```, the value being empty if there's none
+    Header {
+        name: BmsSpan<'a>,
+        value: BmsSpan<'a>,
+    },
+    /// A line starting with ```#``` that's none of the above
+    Unknown,
+    /// Any other line, which BMS treats as a comment
+    Comment,
+}
+
+/// Figures out what a line of a chart is without allocating.
+///
+/// Control commands are matched ignoring case, and whitespace
+/// at the end of the line is ignored.
+pub fn lex_line(line: &str) -> BmsToken<'_> {
+    let line = line.trim_end();
+    let Some(rest) = line.strip_prefix('#') else {
+        return BmsToken::Comment;
+    };
+
+    let name_end = rest
+        .find(|c: char| c.is_whitespace())
+        .map_or(line.len(), |v| v + 1);
+    let name = &line[1..name_end];
+
+    for (control, control_name, takes_argument) in BmsControl::ALL {
+        if name.eq_ignore_ascii_case(control_name) {
+            let argument = takes_argument.then(|| {
+                let start = line.len() - line[name_end..].trim_start().len();
+                BmsSpan::new(line, start, line.len())
+            });
+            return BmsToken::Control { control, argument };
+        }
+    }
+
+    if let Some(token) = lex_channel(line) {
+        return token;
+    }
+
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return BmsToken::Unknown;
+    }
+    let value_start = line.len() - line[name_end..].trim_start().len();
+    BmsToken::Header {
+        name: BmsSpan::new(line, 1, name_end),
+        value: BmsSpan::new(line, value_start, line.len()),
+    }
+}
+
+/// Lexes ```#xxxCC:values``` and ```#EXT #xxxCC:values``` lines
+fn lex_channel(line: &str) -> Option<BmsToken<'_>> {
+    let mut start = 1;
+    let rest = &line[start..];
+    if rest.get(..3).is_some_and(|v| v.eq_ignore_ascii_case("EXT")) {
+        let after_ext = rest[3..].trim_start();
+        if after_ext.len() < rest.len() - 3 {
+            start = line.len() - after_ext.strip_prefix('#')?.len();
+        }
+    }
+
+    let bytes = line.as_bytes();
+    if bytes.len() < start + 3 || !bytes[start..start + 3].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let measure = BmsSpan::new(line, start, start + 3);
+
+    let channel_start = start + 3;
+    let mut chars = line[channel_start..].char_indices();
+    let (_, first) = chars.next()?;
+    let (_, second) = chars.next()?;
+    let (colon, ':') = chars.next()? else {
+        return None;
+    };
+    if first.is_whitespace() || second.is_whitespace() {
+        return None;
+    }
+    let channel = BmsSpan::new(line, channel_start, channel_start + colon);
+    let values = BmsSpan::new(line, channel_start + colon + 1, line.len());
+
+    if channel.text == "02" {
+        Some(BmsToken::TimeSignature {
+            measure,
+            value: values,
+        })
+    } else {
+        Some(BmsToken::Channel {
+            measure,
+            channel,
+            values,
+        })
+    }
+}
+
+/// Decodes a pair of base 36 digits, like object values and channels
+pub fn decode_base36_pair(high: char, low: char) -> Option<u16> {
+    Some((high.to_digit(36)? * 36 + low.to_digit(36)?) as u16)
+}
+
+/// Decodes a pair of hexadecimal digits, like the BPMs of channel 03
+pub fn decode_hex_pair(high: char, low: char) -> Option<u16> {
+    Some((high.to_digit(16)? * 16 + low.to_digit(16)?) as u16)
+}
+
+/// How object values and ids are written, set with ```#BASE```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum BmsBase {
+    /// ```0-9``` and ```A-Z```, ignoring case
+    #[default]
+    Base36,
+    /// ```0-9```, ```A-Z``` then ```a-z```, so ```aa``` and ```AA```
+    /// are different ids
+    Base62,
+}
+
+impl BmsBase {
+    /// The base of a ```#BASE``` header's value, if it's one we know
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value {
+            "36" => Some(BmsBase::Base36),
+            "62" => Some(BmsBase::Base62),
+            _ => None,
+        }
+    }
+
+    fn digit(&self, c: char) -> Option<u32> {
+        match self {
+            BmsBase::Base36 => c.to_digit(36),
+            BmsBase::Base62 => match c {
+                '0'..='9' | 'A'..='Z' => c.to_digit(36),
+                'a'..='z' => Some(c as u32 - 'a' as u32 + 36),
+                _ => None,
+            },
+        }
+    }
+
+    /// The number of different digits
+    pub fn radix(&self) -> u32 {
+        match self {
+            BmsBase::Base36 => 36,
+            BmsBase::Base62 => 62,
+        }
+    }
+
+    /// Decodes a pair of digits, like object values
+    pub fn decode_pair(&self, high: char, low: char) -> Option<u16> {
+        Some((self.digit(high)? * self.radix() + self.digit(low)?) as u16)
+    }
+
+    /// Writes an id as two digits, the opposite of ```decode_id```
+    pub fn encode_id(&self, id: u16) -> String {
+        const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+        let radix = self.radix() as usize;
+        let id = id as usize % (radix * radix);
+        [DIGITS[id / radix] as char, DIGITS[id % radix] as char]
+            .iter()
+            .collect()
+    }
+
+    /// Decodes a two character id, like the ```01``` of ```#WAV01```
+    pub fn decode_id(&self, id: &str) -> Option<u16> {
+        let mut chars = id.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some(high), Some(low), None) => self.decode_pair(high, low),
+            _ => None,
+        }
+    }
+}
+
+/// A kind of header that defines something for an id,
+/// like ```#WAVxx``` or ```#BPMxx```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsIdKind {
+    /// ```#WAVxx```, a keysound
+    Wav,
+    /// ```#BMPxx```, a BGA image or video
+    Bmp,
+    /// ```#BPMxx```, a BPM used by channel 08
+    Bpm,
+    /// ```#STOPxx```, a stop used by channel 09
+    Stop,
+    /// ```#SCROLLxx```, a scroll speed used by channel SC
+    Scroll,
+}
+
+impl BmsIdKind {
+    pub const ALL: [BmsIdKind; 5] = [
+        BmsIdKind::Wav,
+        BmsIdKind::Bmp,
+        BmsIdKind::Bpm,
+        BmsIdKind::Stop,
+        BmsIdKind::Scroll,
+    ];
+
+    /// The name of the header without its id
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            BmsIdKind::Wav => "WAV",
+            BmsIdKind::Bmp => "BMP",
+            BmsIdKind::Bpm => "BPM",
+            BmsIdKind::Stop => "STOP",
+            BmsIdKind::Scroll => "SCROLL",
+        }
+    }
+
+    /// Which kind of header ```name``` is along with its id, if
+    /// it's one
+    pub fn parse_header(name: &str) -> Option<(BmsIdKind, &str)> {
+        Self::ALL
+            .into_iter()
+            .find_map(|kind| Some((kind, header_id(name, kind.prefix())?)))
+    }
+}
+
+/// If ```name``` is ```prefix``` (ignoring case) followed by a two
+/// character id, like ```WAV01``` or ```BPMAA```, returns the id
+pub fn header_id<'a>(name: &'a str, prefix: &str) -> Option<&'a str> {
+    if name.len() == prefix.len() + 2
+        && name.is_char_boundary(prefix.len())
+        && name[..prefix.len()].eq_ignore_ascii_case(prefix)
+    {
+        Some(&name[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_62_tells_cases_apart() {
+        let base = BmsBase::Base62;
+        assert_eq!(base.decode_id("0z"), Some(61));
+        assert_eq!(base.decode_id("AA"), Some(10 * 62 + 10));
+        assert_eq!(base.decode_id("aa"), Some(36 * 62 + 36));
+        assert_eq!(base.decode_id("zz"), Some(62 * 62 - 1));
+        assert_eq!(base.decode_id("a!"), None);
+        assert_eq!(base.decode_id("a"), None);
+        for id in [0, 61, 630, 2268, 3843] {
+            assert_eq!(base.decode_id(&base.encode_id(id)), Some(id));
+        }
+
+        // Base 36 ignores case
+        assert_eq!(BmsBase::Base36.decode_id("aa"), Some(10 * 36 + 10));
+        assert_eq!(BmsBase::Base36.decode_id("AA"), Some(10 * 36 + 10));
+        assert_eq!(BmsBase::Base36.encode_id(10 * 36 + 10), "AA");
+    }
+
+    #[test]
+    fn hex_pairs_are_decoded() {
+        assert_eq!(decode_hex_pair('F', 'f'), Some(255));
+        assert_eq!(decode_hex_pair('7', '8'), Some(120));
+        assert_eq!(decode_hex_pair('G', '0'), None);
+    }
+}
@@ -1,6 +1,14 @@
 pub mod chart;
+pub mod encoding;
+pub mod events;
+pub mod keymode;
 pub mod keysounds;
+pub mod lexer;
+pub mod metadata;
 pub mod timing;
 pub mod notes;
+pub mod rng;
+pub mod template;
+pub mod variants;
 
 // TODO: Clean up *EVERYTHING*
\ No newline at end of file
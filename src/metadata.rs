@@ -0,0 +1,251 @@
+use crate::chart::BmsChart;
+
+/// A header parsed into a ```T```, along with the text it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmsHeaderValue<T> {
+    pub raw: String,
+    /// ```None``` when ```raw``` couldn't be made sense of
+    pub value: Option<T>,
+}
+
+impl<T> BmsHeaderValue<T> {
+    fn parse(raw: &str, parse: impl FnOnce(&str) -> Option<T>) -> Self {
+        BmsHeaderValue {
+            raw: raw.to_string(),
+            value: parse(raw.trim()),
+        }
+    }
+}
+
+/// ```#RANK```, how strict the judge windows are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsRank {
+    VeryHard,
+    Hard,
+    Normal,
+    Easy,
+}
+
+impl BmsRank {
+    pub fn from_number(number: i64) -> Option<Self> {
+        match number {
+            0 => Some(BmsRank::VeryHard),
+            1 => Some(BmsRank::Hard),
+            2 => Some(BmsRank::Normal),
+            3 => Some(BmsRank::Easy),
+            _ => None,
+        }
+    }
+}
+
+/// ```#DIFFICULTY```, the name of the chart's difficulty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsDifficulty {
+    Beginner,
+    Normal,
+    Hyper,
+    Another,
+    Insane,
+}
+
+impl BmsDifficulty {
+    pub fn from_number(number: i64) -> Option<Self> {
+        match number {
+            1 => Some(BmsDifficulty::Beginner),
+            2 => Some(BmsDifficulty::Normal),
+            3 => Some(BmsDifficulty::Hyper),
+            4 => Some(BmsDifficulty::Another),
+            5 => Some(BmsDifficulty::Insane),
+            _ => None,
+        }
+    }
+}
+
+/// ```#PLAYER```, which sides of the play field the chart uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsPlayer {
+    /// 1P only
+    Single,
+    /// Both sides, played by two people
+    Couple,
+    /// Both sides, played by one person
+    Double,
+    /// 1P's chart mirrored on both sides
+    Battle,
+}
+
+impl BmsPlayer {
+    pub fn from_number(number: i64) -> Option<Self> {
+        match number {
+            1 => Some(BmsPlayer::Single),
+            2 => Some(BmsPlayer::Couple),
+            3 => Some(BmsPlayer::Double),
+            4 => Some(BmsPlayer::Battle),
+            _ => None,
+        }
+    }
+}
+
+/// ```#LNTYPE```, how the long note channels (5x/6x) are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsLnType {
+    /// Every pair of objects is the start and end of a long note
+    Rdm,
+    /// A long note lasts for as long as there are objects in a row
+    Mgq,
+}
+
+impl BmsLnType {
+    pub fn from_number(number: i64) -> Option<Self> {
+        match number {
+            1 => Some(BmsLnType::Rdm),
+            2 => Some(BmsLnType::Mgq),
+            _ => None,
+        }
+    }
+}
+
+/// ```#LNMODE```, how long notes are judged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BmsLnMode {
+    /// Only the start is judged, releasing early is a miss
+    Ln,
+    /// Charge notes, the end is judged too
+    Cn,
+    /// Hell charge notes, judged for as long as they're held
+    Hcn,
+}
+
+impl BmsLnMode {
+    pub fn from_number(number: i64) -> Option<Self> {
+        match number {
+            1 => Some(BmsLnMode::Ln),
+            2 => Some(BmsLnMode::Cn),
+            3 => Some(BmsLnMode::Hcn),
+            _ => None,
+        }
+    }
+}
+
+/// The headers that describe a chart, parsed into their types
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BmsMetadata {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub artist: Option<String>,
+    /// Every ```#SUBARTIST```, in order
+    pub subartists: Vec<String>,
+    pub genre: Option<String>,
+    pub bpm: Option<BmsHeaderValue<f64>>,
+    pub play_level: Option<BmsHeaderValue<u32>>,
+    pub rank: Option<BmsHeaderValue<BmsRank>>,
+    /// Judge windows as a percentage of the default ones
+    pub def_ex_rank: Option<BmsHeaderValue<f64>>,
+    pub total: Option<BmsHeaderValue<f64>>,
+    pub difficulty: Option<BmsHeaderValue<BmsDifficulty>>,
+    pub player: Option<BmsHeaderValue<BmsPlayer>>,
+    pub stage_file: Option<String>,
+    pub banner: Option<String>,
+    pub back_bmp: Option<String>,
+    pub preview: Option<String>,
+    pub ln_type: Option<BmsHeaderValue<BmsLnType>>,
+    pub ln_mode: Option<BmsHeaderValue<BmsLnMode>>,
+}
+
+impl BmsMetadata {
+    /// Builds the metadata out of headers given in the order they
+    /// appear in the chart, like ```BmsChart::header_lines```.
+    ///
+    /// Later headers replace earlier ones, except ```#SUBARTIST```
+    /// which can be there more than once.
+    pub fn from_headers<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut metadata = BmsMetadata::default();
+        for (name, value) in headers {
+            match name.to_ascii_uppercase().as_str() {
+                "TITLE" => metadata.title = Some(value.to_string()),
+                "SUBTITLE" => metadata.subtitle = Some(value.to_string()),
+                "ARTIST" => metadata.artist = Some(value.to_string()),
+                "SUBARTIST" => metadata.subartists.push(value.to_string()),
+                "GENRE" => metadata.genre = Some(value.to_string()),
+                "BPM" => metadata.bpm = Some(BmsHeaderValue::parse(value, parse_number)),
+                "PLAYLEVEL" => {
+                    metadata.play_level = Some(BmsHeaderValue::parse(value, |v| {
+                        parse_integer(v)?.try_into().ok()
+                    }))
+                }
+                "RANK" => {
+                    metadata.rank = Some(BmsHeaderValue::parse(value, |v| {
+                        BmsRank::from_number(parse_integer(v)?)
+                    }))
+                }
+                "DEFEXRANK" => {
+                    metadata.def_ex_rank = Some(BmsHeaderValue::parse(value, parse_number))
+                }
+                "TOTAL" => metadata.total = Some(BmsHeaderValue::parse(value, parse_number)),
+                "DIFFICULTY" => {
+                    metadata.difficulty = Some(BmsHeaderValue::parse(value, |v| {
+                        BmsDifficulty::from_number(parse_integer(v)?)
+                    }))
+                }
+                "PLAYER" => {
+                    metadata.player = Some(BmsHeaderValue::parse(value, |v| {
+                        BmsPlayer::from_number(parse_integer(v)?)
+                    }))
+                }
+                "STAGEFILE" => metadata.stage_file = Some(value.to_string()),
+                "BANNER" => metadata.banner = Some(value.to_string()),
+                "BACKBMP" => metadata.back_bmp = Some(value.to_string()),
+                "PREVIEW" => metadata.preview = Some(value.to_string()),
+                "LNTYPE" => {
+                    metadata.ln_type = Some(BmsHeaderValue::parse(value, |v| {
+                        BmsLnType::from_number(parse_integer(v)?)
+                    }))
+                }
+                "LNMODE" => {
+                    metadata.ln_mode = Some(BmsHeaderValue::parse(value, |v| {
+                        BmsLnMode::from_number(parse_integer(v)?)
+                    }))
+                }
+                _ => {}
+            }
+        }
+        metadata
+    }
+}
+
+/// Generates the ```BmsMetadata``` of a ```BmsChart```
+pub fn generate_metadata(chart: &BmsChart) -> BmsMetadata {
+    BmsMetadata::from_headers(
+        chart
+            .header_lines
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    )
+}
+
+/// Parses the number at the start of ```text```, so things like
+/// ```12 (hard)``` or ```150bpm``` still work
+fn parse_number(text: &str) -> Option<f64> {
+    if let Ok(v) = text.parse::<f64>() {
+        // "inf" and "NaN" parse, but aren't numbers any header means
+        return v.is_finite().then_some(v);
+    }
+    let mut end = 0;
+    let mut seen_dot = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '+' | '-' if i == 0 => {}
+            '.' if !seen_dot => seen_dot = true,
+            '0'..='9' => {}
+            _ => break,
+        }
+        end = i + c.len_utf8();
+    }
+    text[..end].parse().ok()
+}
+
+/// Parses the whole number at the start of ```text```, dropping
+/// anything after a decimal point
+fn parse_integer(text: &str) -> Option<i64> {
+    parse_number(text).map(|v| v.trunc() as i64)
+}
@@ -1,175 +1,547 @@
-use std::{f64::NAN, ops::RangeInclusive, vec};
+use std::collections::{HashMap, HashSet};
 
 use ordered_float::OrderedFloat;
-use unicase::UniCase;
 
 use crate::{
     chart::{BmsChart, BmsObject},
+    keymode::{split_note_channel, BmsNoteChannel, BmsSide, KeyMode},
+    lexer::BmsIdKind,
+    metadata::{generate_metadata, BmsLnMode, BmsLnType},
     timing::BmsTime,
 };
 
 #[derive(Debug, PartialEq)]
 pub enum BmsNoteType {
-    Normal { keysound: u16 },
-    Hidden { keysound: u16 },
-    Long { keysound: u16, end_time: BmsTime },
-    Mine { damage: u16 },
-    BGM { keysound: u16 },
+    Normal {
+        keysound: u16,
+    },
+    Hidden {
+        keysound: u16,
+    },
+    Long {
+        keysound: u16,
+        end_time: BmsTime,
+        /// How the long note is judged
+        mode: BmsLnMode,
+        /// How it's spun when it's on a scratch lane
+        spin: Option<BmsScratchSpin>,
+    },
+    /// A landmine, which hurts when its lane is pressed as it
+    /// passes the judge line and should otherwise be avoided
+    Mine {
+        damage: BmsGaugeAmount,
+        /// Empties the gauge no matter how much is left
+        instant_kill: bool,
+        /// What plays when it goes off, ```None``` for nothing
+        keysound: Option<u16>,
+    },
+    BGM {
+        keysound: u16,
+    },
 }
 
-#[derive(Debug)]
+/// How a long note on a scratch lane is played
+#[derive(Debug, Clone, PartialEq)]
+pub enum BmsScratchSpin {
+    /// Backspin scratch (BSS), spun one way at the start and the
+    /// other way to release it
+    Backspin,
+    /// Multi-spin scratch (MSS), a backspin scratch that also
+    /// changes direction at every one of ```reversals```
+    MultiSpin { reversals: Vec<BmsTime> },
+}
+
+impl BmsNoteType {
+    /// When the release of a long note is judged: its end for
+    /// CN and HCN, and never for LN (or any other note)
+    pub fn tail_judgement_time(&self) -> Option<BmsTime> {
+        match self {
+            BmsNoteType::Long {
+                end_time,
+                mode: BmsLnMode::Cn | BmsLnMode::Hcn,
+                ..
+            } => Some(*end_time),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct BmsNote {
     pub hit_time: BmsTime,
+    /// The index of the note's ```BmsLane```, always 0 for BGM
     pub lane: u16,
+    pub scratch: bool,
+    pub side: BmsSide,
     pub note_type: BmsNoteType,
 }
 
-// TODO: Clean up
-/// Generates a ```Vec``` of ```BmsNote``` out of a ```BmsChart```
-pub fn generate_notes(chart: &BmsChart) -> Vec<BmsNote> {
-    const RANGES: [RangeInclusive<u16>; 9] = [
-        // Comments will show range in base 36 for clarity
-        01..=01,   // BGM: 01
-        37..=71,   // 1P Visible: 11..=1Z
-        73..=107,  // 2P Visible: 21..=2Z
-        109..=143, // 1P Invisible: 31..=3Z
-        145..=179, // 2P Invisible: 41..=4Z
-        181..=215, // 1P Longnote: 51..=5Z
-        217..=251, // 2P Longnote: 61..=6Z
-        469..=477, // 1P Landmine: D1..=D9
-        505..=513, // 2P Landmine: E1..=E9
-    ];
+/// An amount of the gauge
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct BmsGaugeAmount {
+    /// Percent of a full gauge
+    pub percent: f64,
+}
+
+/// How the value of a landmine object turns into damage.
+///
+/// In both, ```ZZ``` kills instantly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum BmsMineInterpretation {
+    /// Every 1 of the value is 0.5% of the gauge
+    #[default]
+    Lr2,
+    /// The value is the percent of the gauge
+    Beatoraja,
+}
+
+impl BmsMineInterpretation {
+    fn damage(&self, value: u16) -> BmsGaugeAmount {
+        let percent = match self {
+            BmsMineInterpretation::Lr2 => value as f64 / 2.0,
+            BmsMineInterpretation::Beatoraja => value as f64,
+        };
+        BmsGaugeAmount { percent }
+    }
+}
+
+/// What a game can choose about how notes are generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BmsNoteOptions {
+    pub key_mode: KeyMode,
+    /// Judges every long note this way instead of how the
+    /// chart's ```#LNMODE``` says
+    pub ln_mode: Option<BmsLnMode>,
+    pub mine_interpretation: BmsMineInterpretation,
+    /// The keysound of landmines when the chart has no ```#WAV00```
+    pub default_mine_keysound: Option<u16>,
+}
+
+impl BmsNoteOptions {
+    pub fn new(key_mode: KeyMode) -> Self {
+        BmsNoteOptions {
+            key_mode,
+            ln_mode: None,
+            mine_interpretation: BmsMineInterpretation::default(),
+            default_mine_keysound: None,
+        }
+    }
+}
+
+/// Generates a ```Vec``` of ```BmsNote``` out of a ```BmsChart```,
+/// putting notes in the lanes of ```key_mode```.
+///
+/// Notes on channels that aren't part of ```key_mode```
+/// (like 2P's in ```KeyMode::Key7```) are left out.
+pub fn generate_notes(chart: &BmsChart, key_mode: KeyMode) -> Vec<BmsNote> {
+    generate_notes_with_options(chart, BmsNoteOptions::new(key_mode))
+}
+
+/// What a lane has going on while sweeping through the objects
+#[derive(Default)]
+struct LaneState {
+    /// The slot of the long note (5x/6x) being held on the lane
+    open_ln: Option<usize>,
+    /// The slots of the normal notes while ```open_ln``` is held,
+    /// which are where a multi-spin scratch reverses
+    reversals: Vec<usize>,
+}
+
+/// The order objects at the same time are looked at in, so that
+/// long notes end before a note on their lane is checked against them
+fn channel_order(channel: u16) -> u8 {
+    match split_note_channel(channel) {
+        Some((BmsNoteChannel::Long, ..)) => 0,
+        Some((BmsNoteChannel::Visible, ..)) => 1,
+        Some((BmsNoteChannel::Invisible, ..)) => 2,
+        Some((BmsNoteChannel::Mine, ..)) => 3,
+        None => 4,
+    }
+}
 
+/// Generates a ```Vec``` of ```BmsNote``` out of a ```BmsChart```
+/// like ```generate_notes```, as ```options``` says.
+///
+/// Long notes are judged as LN unless the chart has an ```#LNMODE```
+/// or ```options``` forces a mode.
+///
+/// Notes are in order of time, and then of channel (long, visible,
+/// invisible, mine and BGM, each by number) when they're at the same
+/// time.
+pub fn generate_notes_with_options(chart: &BmsChart, options: BmsNoteOptions) -> Vec<BmsNote> {
     // Filter out objects in channels we aren't interested in
     let mut objects: Vec<&BmsObject> = chart
         .objects
         .iter()
-        .filter(|obj| {
-            for range in RANGES {
-                if range.contains(&obj.channel) {
-                    return true;
-                }
-            }
-            return false;
-        })
+        .filter(|v| v.channel == 1 || split_note_channel(v.channel).is_some())
         .collect();
-    objects.sort();
-
-    // Make it something invalid when it doesn't exist
-    let invalid_lnobj_string = "/".to_string();
-    let lnobj_string = chart
-        .headers
-        .get(&UniCase::from("LNOBJ"))
-        .unwrap_or(&invalid_lnobj_string);
-    let lnobj = u16::from_str_radix(lnobj_string, 16);
-
-    let mut notes: Vec<BmsNote> = vec![];
-    for i in 0..objects.len() {
-        let object: &BmsObject = objects[i];
-
-        let mut lane = 0;
-        // We find the note type by searching the channel ranges
-        let mut note_type = BmsNoteType::Normal { keysound: 0 };
-
-        for j in 0..RANGES.len() {
-            let range = &RANGES[j];
-            if range.contains(&object.channel) {
-                note_type = match j {
-                    0 => BmsNoteType::BGM {
-                        keysound: object.value,
-                    },
-                    1 | 2 => BmsNoteType::Normal {
-                        keysound: object.value,
-                    },
-                    3 | 4 => BmsNoteType::Hidden {
-                        keysound: object.value,
-                    },
-                    5 | 6 => BmsNoteType::Long {
-                        keysound: object.value,
-                        end_time: BmsTime {
-                            measure: 0,
-                            fraction: OrderedFloat(NAN),
-                        },
-                    },
-                    7 | 8 => BmsNoteType::Mine {
-                        damage: object.value / 2, // BMS CMD MEMO says to this idk...
-                    },
-                    _ => unreachable!(),
-                };
+    // Stable, so BGM objects at the same time keep their order
+    objects.sort_by_key(|v| (v.time, channel_order(v.channel), v.channel));
+
+    // Objects on visible channels with these values end the
+    // long note started by the note before them
+    let lnobjs: HashSet<u16> = chart
+        .header_lines
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("LNOBJ"))
+        .filter_map(|(_, value)| chart.base.decode_id(value.trim()))
+        .collect();
+    let metadata = generate_metadata(chart);
+    let ln_type = metadata
+        .ln_type
+        .and_then(|v| v.value)
+        .unwrap_or(BmsLnType::Rdm);
+    let mine_keysound = if chart.id_headers.contains_key(&(BmsIdKind::Wav, 0)) {
+        Some(0)
+    } else {
+        options.default_mine_keysound
+    };
+    let instant_kill_value = chart.base.decode_pair('Z', 'Z');
+    let ln_mode = options
+        .ln_mode
+        .or(metadata.ln_mode.and_then(|v| v.value))
+        .unwrap_or(BmsLnMode::Ln);
+
+    // A note becomes long when the next object on its channel is
+    // an LNOBJ, which is easier to see going backwards
+    let mut lnobj_ends: Vec<Option<BmsTime>> = vec![None; objects.len()];
+    let mut next_objects: HashMap<u16, &BmsObject> = HashMap::new();
+    for (i, object) in objects.iter().enumerate().rev() {
+        if let Some(next) = next_objects.insert(object.channel, object) {
+            if lnobjs.contains(&next.value) && !lnobjs.contains(&object.value) {
+                lnobj_ends[i] = Some(next.time);
             }
         }
+    }
 
-        // Since LNOBJ exists, it could be used for longnotes
-        // and so, we check if the next note's channel is lnobj
-        // We also make sure there IS a next object
-        // We can't forget that if it isn't a normal note, then
-        // it shouldn't be long as well
-        match note_type {
-            BmsNoteType::Normal { keysound } => {
-                if RANGES[0].contains(&object.channel) {
-                    lane = object.channel - RANGES[0].start();
-                } else if RANGES[1].contains(&object.channel) {
-                    lane = object.channel - RANGES[1].start();
-                }
-                if i < objects.len() - 1
-                    && lnobj.as_ref().is_ok()
-                    && object.value != *lnobj.as_ref().unwrap()
-                {
-                    let lnobj = *lnobj.as_ref().unwrap();
-                    if let Some(next_idx) = objects.iter().position(|e| {
-                        e.channel == object.channel && e.value == lnobj && e.time > object.time
-                    }) {
-                        note_type = BmsNoteType::Long {
-                            keysound,
-                            end_time: objects[next_idx].time,
-                        };
+    // Notes that end up not being notes (like the normal notes
+    // that become part of a multi-spin scratch) are taken out of
+    // their slot
+    let mut slots: Vec<Option<BmsNote>> = Vec::with_capacity(objects.len());
+    let mut lanes: HashMap<u16, LaneState> = HashMap::new();
+    for (object, lnobj_end) in objects.into_iter().zip(lnobj_ends) {
+        if object.channel == 1 {
+            slots.push(Some(BmsNote {
+                hit_time: object.time,
+                lane: 0,
+                scratch: false,
+                side: BmsSide::Player1,
+                note_type: BmsNoteType::BGM {
+                    keysound: object.value,
+                },
+            }));
+            continue;
+        }
+        let Some(lane) = options.key_mode.lane(object.channel) else {
+            continue;
+        };
+        // Only note channels get here, BGM was handled above
+        let (kind, ..) = split_note_channel(object.channel).unwrap();
+        let state = lanes.entry(lane.index).or_default();
+        let note_type = match kind {
+            // LNOBJ end markers are part of the note before them
+            BmsNoteChannel::Visible if lnobjs.contains(&object.value) => continue,
+            BmsNoteChannel::Visible if lnobj_end.is_some() => BmsNoteType::Long {
+                keysound: object.value,
+                end_time: lnobj_end.unwrap(),
+                mode: ln_mode,
+                spin: lane.scratch.then_some(BmsScratchSpin::Backspin),
+            },
+            BmsNoteChannel::Visible => {
+                if let Some(ln) = state.open_ln.filter(|_| lane.scratch) {
+                    // Long notes that are still open always end after
+                    // this, but MGQ ones know where they end already
+                    let ln = slots[ln].as_ref().unwrap();
+                    let inside = object.time > ln.hit_time
+                        && long_end_time(ln)
+                            .is_none_or(|end| end.fraction.is_nan() || object.time < end);
+                    if inside {
+                        state.reversals.push(slots.len());
                     }
                 }
-            }
-            BmsNoteType::Hidden { keysound: _ } => {
-                if RANGES[2].contains(&object.channel) {
-                    lane = object.channel - RANGES[2].start();
-                } else if RANGES[3].contains(&object.channel) {
-                    lane = object.channel - RANGES[3].start();
+                BmsNoteType::Normal {
+                    keysound: object.value,
                 }
             }
-            BmsNoteType::Long {
-                keysound,
-                end_time: _,
-            } => {
-                if RANGES[4].contains(&object.channel) {
-                    lane = object.channel - RANGES[4].start();
-                } else if RANGES[5].contains(&object.channel) {
-                    lane = object.channel - RANGES[5].start();
+            BmsNoteChannel::Invisible => BmsNoteType::Hidden {
+                keysound: object.value,
+            },
+            BmsNoteChannel::Long => {
+                match (ln_type, state.open_ln) {
+                    // Objects pair up into a start and an end
+                    (BmsLnType::Rdm, Some(ln)) => {
+                        set_end_time(slots[ln].as_mut().unwrap(), object.time);
+                        close_ln(&mut slots, state, lane.scratch);
+                        continue;
+                    }
+                    // Objects in a row make up one long note, which
+                    // ends where the last object's cell does. Lines
+                    // can be split differently, so an object can also
+                    // be in the middle of the long note's last cell.
+                    (BmsLnType::Mgq, Some(ln))
+                        if long_end_time(slots[ln].as_ref().unwrap())
+                            .is_some_and(|end| object.time <= end) =>
+                    {
+                        let ln = slots[ln].as_mut().unwrap();
+                        let end_time = long_end_time(ln).unwrap().max(cell_end(object));
+                        set_end_time(ln, end_time);
+                        continue;
+                    }
+                    (BmsLnType::Mgq, Some(_)) => close_ln(&mut slots, state, lane.scratch),
+                    (_, None) => {}
                 }
-                if let Some(next_idx) = objects.iter().position(|e| {
-                    e.channel == object.channel && e.value == object.value && e.time > object.time
-                }) {
-                    let next = objects[next_idx];
-                    note_type = BmsNoteType::Long {
-                        keysound,
-                        end_time: next.time,
-                    };
-                } else {
-                    continue;
+                state.open_ln = Some(slots.len());
+                BmsNoteType::Long {
+                    keysound: object.value,
+                    end_time: match ln_type {
+                        BmsLnType::Rdm => BmsTime {
+                            measure: 0,
+                            fraction: OrderedFloat(f64::NAN),
+                        },
+                        BmsLnType::Mgq => cell_end(object),
+                    },
+                    mode: ln_mode,
+                    spin: None,
                 }
             }
-            BmsNoteType::Mine { damage: _ } => {
-                if RANGES[6].contains(&object.channel) {
-                    lane = object.channel - RANGES[6].start();
-                } else if RANGES[7].contains(&object.channel) {
-                    lane = object.channel - RANGES[7].start();
+            BmsNoteChannel::Mine => {
+                let instant_kill = Some(object.value) == instant_kill_value;
+                BmsNoteType::Mine {
+                    damage: if instant_kill {
+                        BmsGaugeAmount { percent: 100.0 }
+                    } else {
+                        options.mine_interpretation.damage(object.value)
+                    },
+                    instant_kill,
+                    keysound: mine_keysound,
                 }
             }
-            _ => {}
-        }
+        };
 
-        notes.push(BmsNote {
+        slots.push(Some(BmsNote {
             hit_time: object.time,
-            lane,
+            lane: lane.index,
+            scratch: lane.scratch,
+            side: lane.side,
             note_type,
+        }));
+    }
+
+    for (_, mut state) in lanes {
+        if let Some(ln) = state.open_ln {
+            match ln_type {
+                // Long notes without an end are dropped
+                BmsLnType::Rdm => {
+                    slots[ln] = None;
+                    state.open_ln = None;
+                }
+                BmsLnType::Mgq => {
+                    let scratch = slots[ln].as_ref().unwrap().scratch;
+                    close_ln(&mut slots, &mut state, scratch);
+                }
+            }
+        }
+    }
+
+    slots.into_iter().flatten().collect()
+}
+
+/// Closes the long note held on a lane, making it a backspin
+/// scratch if it's on a scratch lane.
+///
+/// Normal notes on the same lane while it was held are where a
+/// multi-spin scratch reverses, so they become part of it.
+fn close_ln(slots: &mut [Option<BmsNote>], state: &mut LaneState, scratch: bool) {
+    let Some(ln) = state.open_ln.take() else {
+        return;
+    };
+    let reversals: Vec<BmsTime> = state
+        .reversals
+        .drain(..)
+        .filter_map(|slot| Some(slots[slot].take()?.hit_time))
+        .collect();
+    if !scratch {
+        return;
+    }
+    if let Some(BmsNoteType::Long { spin, .. }) = slots[ln].as_mut().map(|v| &mut v.note_type) {
+        *spin = Some(if reversals.is_empty() {
+            BmsScratchSpin::Backspin
+        } else {
+            BmsScratchSpin::MultiSpin { reversals }
         });
     }
+}
+
+fn long_end_time(note: &BmsNote) -> Option<BmsTime> {
+    match note.note_type {
+        BmsNoteType::Long { end_time, .. } => Some(end_time),
+        _ => None,
+    }
+}
+
+fn set_end_time(note: &mut BmsNote, time: BmsTime) {
+    if let BmsNoteType::Long { end_time, .. } = &mut note.note_type {
+        *end_time = time;
+    }
+}
+
+/// Where the cell an object is in ends, which is where the
+/// next cell of its line starts
+fn cell_end(object: &BmsObject) -> BmsTime {
+    let divisions = object.divisions.max(1);
+    let cell = (object.time.fraction.0 * divisions as f64).round() as u32 + 1;
+    if cell >= divisions {
+        BmsTime {
+            measure: object.time.measure + 1,
+            fraction: OrderedFloat(0.0),
+        }
+    } else {
+        // Same as the fraction of an object in that cell
+        BmsTime {
+            measure: object.time.measure,
+            fraction: OrderedFloat((1.0 / divisions as f64) * cell as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::ReplayRng;
+
+    fn notes(data: &str) -> Vec<BmsNote> {
+        let chart = BmsChart::compile(data, &mut ReplayRng::from_values(vec![])).unwrap();
+        generate_notes(&chart, KeyMode::Key7)
+    }
+
+    /// The start and end of every long note
+    fn long_notes(notes: &[BmsNote]) -> Vec<(BmsTime, BmsTime)> {
+        notes
+            .iter()
+            .filter_map(|v| Some((v.hit_time, long_end_time(v)?)))
+            .collect()
+    }
+
+    #[test]
+    fn mgq_cells_are_as_long_as_their_own_line_says() {
+        let notes = notes("#LNTYPE 2\n#00151:0101\n#00151:000001\n#00151:0000000000000000000000");
+        assert_eq!(
+            long_notes(&notes),
+            vec![(BmsTime::new(1, 0.0), BmsTime::new(2, 0.0))]
+        );
+    }
+
+    #[test]
+    fn rdm_objects_pair_up_and_unclosed_ones_are_dropped() {
+        let notes = notes("#00151:01000200\n#00251:03");
+        assert_eq!(
+            long_notes(&notes),
+            vec![(BmsTime::new(1, 0.0), BmsTime::new(1, 0.5))]
+        );
+        assert_eq!(notes.len(), 1);
+    }
+
+    #[test]
+    fn lnobj_markers_end_the_note_before_them() {
+        let notes = notes("#LNOBJ ZZ\n#LNOBJ YY\n#00111:01ZZ02YY\n#00112:03");
+        assert_eq!(
+            long_notes(&notes),
+            vec![
+                (BmsTime::new(1, 0.0), BmsTime::new(1, 0.25)),
+                (BmsTime::new(1, 0.5), BmsTime::new(1, 0.75)),
+            ]
+        );
+        // The markers aren't notes of their own
+        assert_eq!(notes.len(), 3);
+        assert_eq!(notes[1].note_type, BmsNoteType::Normal { keysound: 3 });
+    }
+
+    #[test]
+    fn mgq_chains_across_measures() {
+        let notes = notes("#LNTYPE 2\n#00151:000101\n#00251:0100\n#00251:000001");
+        assert_eq!(
+            long_notes(&notes),
+            vec![
+                (BmsTime::new(1, 1.0 / 3.0), BmsTime::new(2, 0.5)),
+                (BmsTime::new(2, 2.0 / 3.0), BmsTime::new(3, 0.0)),
+            ]
+        );
+    }
 
-    notes
+    #[test]
+    fn ln_headers_are_parsed_like_metadata() {
+        let notes = notes("#LNTYPE 2.0\n#LNMODE 2.0\n#00151:0101");
+        assert_eq!(
+            notes[0].note_type,
+            BmsNoteType::Long {
+                keysound: 1,
+                end_time: BmsTime::new(2, 0.0),
+                mode: BmsLnMode::Cn,
+                spin: None,
+            }
+        );
+    }
+
+    #[test]
+    fn scratch_notes_inside_a_scratch_ln_are_reversals() {
+        let notes = notes("#00156:01000001\n#00116:00010100\n#00216:01");
+        assert_eq!(notes.len(), 2);
+        assert_eq!(
+            notes[0].note_type,
+            BmsNoteType::Long {
+                keysound: 1,
+                end_time: BmsTime::new(1, 0.75),
+                mode: BmsLnMode::Ln,
+                spin: Some(BmsScratchSpin::MultiSpin {
+                    reversals: vec![BmsTime::new(1, 0.25), BmsTime::new(1, 0.5)],
+                }),
+            }
+        );
+        assert!(notes[0].scratch);
+        // After the LN, so not a reversal
+        assert_eq!(notes[1].hit_time, BmsTime::new(2, 0.0));
+        assert_eq!(notes[1].note_type, BmsNoteType::Normal { keysound: 1 });
+    }
+
+    #[test]
+    fn scratch_lns_without_reversals_are_backspins() {
+        for data in ["#00156:0101", "#LNOBJ ZZ\n#00116:01ZZ"] {
+            let notes = notes(data);
+            assert_eq!(notes.len(), 1, "{data}");
+            assert!(
+                matches!(
+                    notes[0].note_type,
+                    BmsNoteType::Long {
+                        spin: Some(BmsScratchSpin::Backspin),
+                        ..
+                    }
+                ),
+                "{data}"
+            );
+        }
+        // Only scratch LNs spin
+        let notes = notes("#00151:0101");
+        assert!(matches!(
+            notes[0].note_type,
+            BmsNoteType::Long { spin: None, .. }
+        ));
+    }
+
+    #[test]
+    fn notes_at_the_same_time_are_ordered_by_channel() {
+        let notes =
+            notes("#00101:0A\n#001D1:04\n#00131:03\n#00112:02\n#00111:01\n#00152:05\n#00252:05");
+        let note_types: Vec<&BmsNoteType> = notes.iter().map(|v| &v.note_type).collect();
+        assert!(matches!(
+            note_types[..],
+            [
+                BmsNoteType::Long { keysound: 5, .. },
+                BmsNoteType::Normal { keysound: 1 },
+                BmsNoteType::Normal { keysound: 2 },
+                BmsNoteType::Hidden { keysound: 3 },
+                BmsNoteType::Mine { .. },
+                BmsNoteType::BGM { keysound: 10 },
+            ]
+        ));
+    }
 }
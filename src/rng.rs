@@ -0,0 +1,84 @@
+/// Something that can roll the values of ```#RANDOM``` and
+/// ```#SWITCH``` blocks while compiling a chart.
+///
+/// Any ```FnMut(u32) -> u32``` is a ```BmsRng```, so plain functions
+/// and closures work as well.
+pub trait BmsRng {
+    /// Returns a value between 1 and ```max``` (AKA ```1..=max```)
+    fn roll(&mut self, max: u32) -> u32;
+}
+
+impl<F: FnMut(u32) -> u32> BmsRng for F {
+    fn roll(&mut self, max: u32) -> u32 {
+        self(max)
+    }
+}
+
+/// A value that was rolled while compiling a chart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BmsRoll {
+    /// 1-based line of the ```#RANDOM``` or ```#SWITCH``` that rolled
+    pub line: usize,
+    pub max: u32,
+    pub value: u32,
+}
+
+/// A small seedable random number generator (SplitMix64), so the
+/// same seed always compiles the same variant of a chart
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        SeededRng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl BmsRng for SeededRng {
+    /// Returns 0 when ```max``` is 0, since there's nothing to roll
+    fn roll(&mut self, max: u32) -> u32 {
+        if max == 0 {
+            return 0;
+        }
+        // Scale the top 32 bits into 0..max instead of using modulo
+        (((self.next_u64() >> 32) * max as u64) >> 32) as u32 + 1
+    }
+}
+
+/// Plays back the values in ```BmsChart::rolls``` to compile
+/// exactly the same variant of a chart again
+///
+/// Once every value was played back it always rolls 1
+/// (or 0 if ```max``` is 0).
+#[derive(Debug, Clone)]
+pub struct ReplayRng {
+    values: std::vec::IntoIter<u32>,
+}
+
+impl ReplayRng {
+    pub fn new(rolls: &[BmsRoll]) -> Self {
+        Self::from_values(rolls.iter().map(|v| v.value).collect())
+    }
+
+    pub fn from_values(values: Vec<u32>) -> Self {
+        ReplayRng {
+            values: values.into_iter(),
+        }
+    }
+}
+
+impl BmsRng for ReplayRng {
+    fn roll(&mut self, max: u32) -> u32 {
+        self.values.next().unwrap_or(max.min(1))
+    }
+}
@@ -0,0 +1,1360 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use ordered_float::OrderedFloat;
+use unicase::UniCase;
+
+use crate::{
+    chart::{
+        columns_of, BmsChart, BmsObject, BmsParseError, BmsParseMode, BmsReadError, Diagnostic,
+        Diagnostics, Severity,
+    },
+    encoding::BmsEncoding,
+    lexer::{
+        decode_base36_pair, decode_hex_pair, lex_line, BmsBase, BmsControl, BmsIdKind, BmsSpan,
+        BmsToken,
+    },
+    rng::{BmsRng, BmsRoll},
+    timing::BmsTime,
+};
+
+/// A line of a chart that isn't about control flow
+#[derive(Debug, Clone, PartialEq)]
+pub enum BmsStatement {
+    /// A ```#NAME value``` line
+    Header { name: String, value: String },
+    /// A ```#xxx02:``` line
+    TimeSignature { measure: u16, value: f64 },
+    /// The (non-zero) objects of a ```#xxxCC:``` line
+    Objects(Vec<BmsObject>),
+    /// A header like ```#WAVxx```, with its id already decoded.
+    /// Comes right after the ```Header``` of the same line.
+    Definition {
+        kind: BmsIdKind,
+        id: u16,
+        value: String,
+    },
+}
+
+/// Where the value of a ```#RANDOM``` or ```#SWITCH``` block comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmsRandomValue {
+    /// ```#RANDOM max``` or ```#SWITCH max```, rolled when instantiating
+    Roll { max: u32 },
+    /// ```#SETRANDOM value``` or ```#SETSWITCH value```
+    Set { value: u32 },
+}
+
+/// A branch of an ```#IF```/```#ELSEIF```/```#ELSE``` chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmsBranch {
+    /// The value the ```#RANDOM``` must have for the branch to be
+    /// taken, ```None``` for ```#ELSE```
+    ///
+    /// Values that couldn't be parsed in lenient mode become 0,
+    /// which a ```#RANDOM``` never rolls.
+    pub condition: Option<u32>,
+    pub body: Vec<BmsTemplateNode>,
+}
+
+/// Something inside a ```#SWITCH``` block
+#[derive(Debug, Clone, PartialEq)]
+pub enum BmsSwitchItem {
+    /// ```#CASE value```
+    Case(u32),
+    /// ```#DEF```
+    Default,
+    /// ```#SKIP```
+    Skip,
+    Node(BmsTemplateNode),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BmsTemplateNode {
+    Statement(BmsStatement),
+    /// A ```#RANDOM```/```#SETRANDOM``` block, ended by ```#ENDRANDOM```
+    Random {
+        /// 1-based line of the ```#RANDOM```
+        line: usize,
+        value: BmsRandomValue,
+        body: Vec<BmsTemplateNode>,
+    },
+    /// An ```#IF```/```#ELSEIF```/```#ELSE``` chain, ended by ```#ENDIF```.
+    /// At most one of the branches is taken.
+    If {
+        branches: Vec<BmsBranch>,
+    },
+    /// A ```#SWITCH```/```#SETSWITCH``` block, ended by ```#ENDSW```.
+    ///
+    /// Runs from the first ```#CASE``` matching the value (or the
+    /// first ```#DEF``` if none does), falling through any other
+    /// labels, until a ```#SKIP```.
+    Switch {
+        /// 1-based line of the ```#SWITCH```
+        line: usize,
+        value: BmsRandomValue,
+        body: Vec<BmsSwitchItem>,
+    },
+}
+
+/// A parsed chart with all of its ```#RANDOM``` and ```#SWITCH```
+/// branches still in it.
+///
+/// Parsing is the slow part, so parse once and ```instantiate```
+/// every time a new roll is needed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BmsTemplate {
+    pub nodes: Vec<BmsTemplateNode>,
+    /// The last ```#BASE``` of the chart
+    pub base: BmsBase,
+}
+
+/// A control flow block that is still open while parsing
+enum OpenBlock {
+    Random {
+        line: usize,
+        value: BmsRandomValue,
+        body: Vec<BmsTemplateNode>,
+    },
+    If {
+        branches: Vec<BmsBranch>,
+    },
+    Switch {
+        line: usize,
+        value: BmsRandomValue,
+        body: Vec<BmsSwitchItem>,
+    },
+}
+
+impl OpenBlock {
+    fn into_node(self) -> BmsTemplateNode {
+        match self {
+            OpenBlock::Random { line, value, body } => {
+                BmsTemplateNode::Random { line, value, body }
+            }
+            OpenBlock::If { branches } => BmsTemplateNode::If { branches },
+            OpenBlock::Switch { line, value, body } => {
+                BmsTemplateNode::Switch { line, value, body }
+            }
+        }
+    }
+}
+
+/// Where a ```TemplateParser``` puts what it parses
+trait TemplateSink {
+    fn statement(&mut self, statement: BmsStatement);
+
+    /// Opens, continues or closes a control flow block, returning
+    /// whether there's a block for ```control``` to go in
+    fn control(&mut self, line_number: usize, control: BmsControl, argument: Option<u32>) -> bool;
+
+    /// Whether an ```#IF``` here has a ```#RANDOM``` to check against
+    fn in_random(&self) -> bool;
+}
+
+/// Builds the tree of a ```BmsTemplate``` one line at a time
+#[derive(Default)]
+struct TemplateBuilder {
+    nodes: Vec<BmsTemplateNode>,
+    /// Every block that's open, innermost last
+    open: Vec<OpenBlock>,
+}
+
+impl TemplateBuilder {
+    fn push(&mut self, node: BmsTemplateNode) {
+        match self.open.last_mut() {
+            None => self.nodes.push(node),
+            Some(OpenBlock::Random { body, .. }) => body.push(node),
+            // An #IF block always has at least one branch
+            Some(OpenBlock::If { branches }) => branches.last_mut().unwrap().body.push(node),
+            Some(OpenBlock::Switch { body, .. }) => body.push(BmsSwitchItem::Node(node)),
+        }
+    }
+
+    fn open(&mut self, block: OpenBlock) {
+        self.open.push(block);
+    }
+
+    /// Closes the innermost block ```is_block``` returns true for,
+    /// along with anything left open inside of it. Returns whether
+    /// there's such a block.
+    fn close(&mut self, is_block: fn(&OpenBlock) -> bool) -> bool {
+        if !self.close_inside(is_block) {
+            return false;
+        }
+        let block = self.open.pop().unwrap();
+        self.push(block.into_node());
+        true
+    }
+
+    /// Closes anything left open inside the innermost block
+    /// ```is_block``` returns true for, so that it's the innermost
+    /// one. Returns whether there's such a block.
+    fn close_inside(&mut self, is_block: fn(&OpenBlock) -> bool) -> bool {
+        let Some(index) = self.open.iter().rposition(is_block) else {
+            return false;
+        };
+        while self.open.len() > index + 1 {
+            let block = self.open.pop().unwrap();
+            self.push(block.into_node());
+        }
+        true
+    }
+
+    fn finish(mut self) -> BmsTemplate {
+        self.close(|_| true);
+        BmsTemplate {
+            nodes: self.nodes,
+            base: BmsBase::default(),
+        }
+    }
+}
+
+impl TemplateSink for TemplateBuilder {
+    fn statement(&mut self, statement: BmsStatement) {
+        self.push(BmsTemplateNode::Statement(statement));
+    }
+
+    fn control(&mut self, line_number: usize, control: BmsControl, argument: Option<u32>) -> bool {
+        match control {
+            BmsControl::Random
+            | BmsControl::SetRandom
+            | BmsControl::Switch
+            | BmsControl::SetSwitch => {
+                let value = random_value(control, argument);
+                self.open(match control {
+                    BmsControl::Random | BmsControl::SetRandom => OpenBlock::Random {
+                        line: line_number,
+                        value,
+                        body: vec![],
+                    },
+                    _ => OpenBlock::Switch {
+                        line: line_number,
+                        value,
+                        body: vec![],
+                    },
+                });
+                true
+            }
+            BmsControl::EndRandom => self.close(|v| matches!(v, OpenBlock::Random { .. })),
+            BmsControl::If => {
+                self.open(OpenBlock::If {
+                    branches: vec![BmsBranch {
+                        // Nothing matches 0, but an #ELSEIF or #ELSE still could
+                        condition: Some(argument.unwrap_or(0)),
+                        body: vec![],
+                    }],
+                });
+                true
+            }
+            BmsControl::ElseIf | BmsControl::Else => {
+                let condition = match control {
+                    BmsControl::ElseIf => Some(argument.unwrap_or(0)),
+                    _ => None,
+                };
+                // Like #ENDIF, this closes a #RANDOM left open in the
+                // branch before it
+                let matched = self.close_inside(|v| matches!(v, OpenBlock::If { .. }));
+                if let Some(OpenBlock::If { branches }) = self.open.last_mut() {
+                    branches.push(BmsBranch {
+                        condition,
+                        body: vec![],
+                    });
+                }
+                matched
+            }
+            BmsControl::EndIf => self.close(|v| matches!(v, OpenBlock::If { .. })),
+            BmsControl::Case | BmsControl::Skip | BmsControl::Def => {
+                let item = match control {
+                    BmsControl::Case => match argument {
+                        Some(v) => BmsSwitchItem::Case(v),
+                        // Already reported as an invalid argument
+                        None => return true,
+                    },
+                    BmsControl::Skip => BmsSwitchItem::Skip,
+                    _ => BmsSwitchItem::Default,
+                };
+                // A #RANDOM or #IF left open above a label ends there
+                let matched = self.close_inside(|v| matches!(v, OpenBlock::Switch { .. }));
+                if let Some(OpenBlock::Switch { body, .. }) = self.open.last_mut() {
+                    body.push(item);
+                }
+                matched
+            }
+            BmsControl::EndSwitch => self.close(|v| matches!(v, OpenBlock::Switch { .. })),
+        }
+    }
+
+    fn in_random(&self) -> bool {
+        self.open
+            .iter()
+            .any(|v| matches!(v, OpenBlock::Random { .. }))
+    }
+}
+
+/// The value of a ```#RANDOM```/```#SETRANDOM``` or
+/// ```#SWITCH```/```#SETSWITCH``` with ```argument```
+fn random_value(control: BmsControl, argument: Option<u32>) -> BmsRandomValue {
+    match argument {
+        Some(max) if matches!(control, BmsControl::Random | BmsControl::Switch) => {
+            BmsRandomValue::Roll { max }
+        }
+        Some(value) => BmsRandomValue::Set { value },
+        // No #IF or #CASE can match this, so only
+        // #ELSE or #DEF will run
+        None => BmsRandomValue::Set { value: 0 },
+    }
+}
+
+/// Parses a chart one line at a time, into a ```BmsTemplate``` or
+/// whatever else ```sink``` makes of it
+struct TemplateParser<S: TemplateSink = TemplateBuilder> {
+    diagnostics: Diagnostics,
+    sink: S,
+    /// Skips time signatures and objects without decoding them
+    headers_only: bool,
+    /// The base of the ```#BASE``` above the current line
+    base: BmsBase,
+}
+
+impl<S: TemplateSink> TemplateParser<S> {
+    fn new(mode: BmsParseMode, sink: S) -> Self {
+        TemplateParser {
+            diagnostics: Diagnostics::new(mode),
+            sink,
+            headers_only: false,
+            base: BmsBase::default(),
+        }
+    }
+
+    /// Parses the 1-based ```line_number```th line of the chart
+    fn parse_line(&mut self, line_number: usize, line: &str) -> Result<(), BmsParseError> {
+        // Where a span is, along with its text
+        let span = |span: BmsSpan| {
+            (
+                line_number,
+                columns_of(line, span.start, span.end()),
+                span.text.to_string(),
+            )
+        };
+
+        match lex_line(line) {
+            BmsToken::Control { control, argument } => {
+                // Only the controls that take an argument have one
+                let argument = match argument {
+                    Some(argument) => match argument.text.parse::<u32>() {
+                        Ok(v) => Some(v),
+                        Err(_) => {
+                            let (line, columns, text) = span(argument);
+                            self.diagnostics.recover(BmsParseError::InvalidRandom {
+                                line,
+                                columns,
+                                text,
+                            })?;
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                if control == BmsControl::If && !self.sink.in_random() {
+                    let text = line.trim_end();
+                    self.diagnostics.recover(BmsParseError::OrphanedIf {
+                        line: line_number,
+                        columns: columns_of(line, 0, text.len()),
+                        text: text.to_string(),
+                    })?;
+                    // Without a #RANDOM no branch of the chain is ever taken
+                }
+                self.parse_control(line_number, control, argument);
+            }
+            BmsToken::TimeSignature { .. } | BmsToken::Channel { .. } if self.headers_only => {}
+            BmsToken::TimeSignature { measure, value } => {
+                let measure: u16 = match measure.text.parse() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        let (line, columns, text) = span(measure);
+                        return self.diagnostics.recover(BmsParseError::InvalidMeasure {
+                            line,
+                            columns,
+                            text,
+                        });
+                    }
+                };
+                let time_signature: f64 = match value.text.parse() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        let (line, columns, text) = span(value);
+                        return self
+                            .diagnostics
+                            .recover(BmsParseError::InvalidTimeSignature {
+                                line,
+                                columns,
+                                text,
+                            });
+                    }
+                };
+                self.sink.statement(BmsStatement::TimeSignature {
+                    measure,
+                    value: time_signature,
+                });
+            }
+            BmsToken::Channel {
+                measure,
+                channel,
+                values,
+            } => {
+                let measure_value: u16 = match measure.text.parse() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        let (line, columns, text) = span(measure);
+                        return self.diagnostics.recover(BmsParseError::InvalidMeasure {
+                            line,
+                            columns,
+                            text,
+                        });
+                    }
+                };
+                let mut channel_chars = channel.text.chars();
+                let channel_value = match (channel_chars.next(), channel_chars.next()) {
+                    (Some(high), Some(low)) => decode_base36_pair(high, low),
+                    _ => None,
+                };
+                let Some(channel_value) = channel_value else {
+                    let (line, columns, text) = span(channel);
+                    return self.diagnostics.recover(BmsParseError::InvalidChannel {
+                        line,
+                        columns,
+                        text,
+                    });
+                };
+
+                let values_column = columns_of(line, values.start, values.end()).start;
+                // Values come in pairs so we divide by 2 to get the divisions
+                // in the measure. Counting chars means a stray multi-byte
+                // character can't split a pair in half.
+                let divisions = values.text.chars().count() / 2;
+                let mut objects = vec![];
+                let mut chars = values.text.chars();
+                for i in 0..divisions {
+                    // There are always at least 2 * divisions chars
+                    let high = chars.next().unwrap();
+                    let low = chars.next().unwrap();
+                    let value = match channel_value {
+                        // BPMs are written in hexadecimal
+                        3 => decode_hex_pair(high, low),
+                        _ => self.base.decode_pair(high, low),
+                    };
+                    let Some(value) = value else {
+                        let column = values_column + i * 2;
+                        self.diagnostics.recover(BmsParseError::InvalidObject {
+                            line: line_number,
+                            columns: column..column + 2,
+                            text: [high, low].iter().collect(),
+                        })?;
+                        continue;
+                    };
+                    if value != 0 {
+                        objects.push(BmsObject {
+                            channel: channel_value,
+                            time: BmsTime {
+                                measure: measure_value,
+                                fraction: OrderedFloat((1.0 / divisions as f64) * i as f64),
+                            },
+                            value,
+                            divisions: divisions as u32,
+                        });
+                    }
+                }
+                self.sink.statement(BmsStatement::Objects(objects));
+            }
+            BmsToken::Header { name, value } => {
+                if name.text.eq_ignore_ascii_case("BASE") {
+                    match BmsBase::from_header(value.text) {
+                        Some(base) => self.base = base,
+                        None => self.diagnostics.note(Diagnostic {
+                            severity: Severity::Warning,
+                            line: line_number,
+                            message: format!("unknown base \"{}\" was ignored", value.text),
+                            code: "unknown_base",
+                        }),
+                    }
+                }
+                self.sink.statement(BmsStatement::Header {
+                    name: name.text.to_string(),
+                    value: value.text.to_string(),
+                });
+                let definition = BmsIdKind::parse_header(name.text)
+                    .and_then(|(kind, id)| Some((kind, self.base.decode_id(id)?)));
+                if let Some((kind, id)) = definition {
+                    self.sink.statement(BmsStatement::Definition {
+                        kind,
+                        id,
+                        value: value.text.to_string(),
+                    });
+                }
+            }
+            BmsToken::Unknown => {
+                self.diagnostics.note(Diagnostic {
+                    severity: Severity::Info,
+                    line: line_number,
+                    message: format!("unknown line \"{}\" was ignored", line),
+                    code: "unknown_line",
+                });
+            }
+            BmsToken::Comment => {}
+        }
+        Ok(())
+    }
+
+    /// Hands a control flow line over to the sink, noting it if
+    /// it's out of place.
+    ///
+    /// ```argument``` is ```None``` for controls without one, or if
+    /// it couldn't be parsed (and the error was recovered from).
+    fn parse_control(&mut self, line_number: usize, control: BmsControl, argument: Option<u32>) {
+        if !self.sink.control(line_number, control, argument) {
+            self.diagnostics.note(Diagnostic {
+                severity: Severity::Warning,
+                line: line_number,
+                message: format!("#{} without a block to go in was ignored", control.name()),
+                code: "unmatched_control",
+            });
+        }
+    }
+}
+
+impl TemplateParser {
+    fn finish(self) -> (BmsTemplate, Vec<Diagnostic>) {
+        let template = BmsTemplate {
+            base: self.base,
+            ..self.sink.finish()
+        };
+        (template, self.diagnostics.into_list())
+    }
+}
+
+impl BmsTemplate {
+    /// Parses a ```BmsTemplate``` from a ```&str```, stopping at the
+    /// first line that can't be parsed.
+    pub fn parse(data: &str) -> Result<BmsTemplate, BmsParseError> {
+        Self::parse_with_mode(data, BmsParseMode::Strict).map(|(template, _)| template)
+    }
+
+    /// Parses a ```BmsTemplate``` from a ```&str```, reacting to
+    /// broken lines as ```mode``` says.
+    ///
+    /// The returned ```Vec<Diagnostic>``` is always empty in strict mode.
+    pub fn parse_with_mode(
+        data: &str,
+        mode: BmsParseMode,
+    ) -> Result<(BmsTemplate, Vec<Diagnostic>), BmsParseError> {
+        let mut parser = TemplateParser::new(mode, TemplateBuilder::default());
+        for (line_index, line) in data.lines().enumerate() {
+            parser.parse_line(line_index + 1, line)?;
+        }
+        Ok(parser.finish())
+    }
+
+    /// Parses a ```BmsTemplate``` from a ```&str``` like
+    /// ```parse_with_mode```, but only keeps the headers and the
+    /// control flow around them.
+    ///
+    /// Time signature and channel lines aren't decoded at all, so
+    /// they can't fail either. The ```#RANDOM``` and ```#SWITCH```
+    /// blocks are all still there, so instantiating it rolls exactly
+    /// like the full template would.
+    pub fn parse_headers_with_mode(
+        data: &str,
+        mode: BmsParseMode,
+    ) -> Result<(BmsTemplate, Vec<Diagnostic>), BmsParseError> {
+        let mut parser = TemplateParser {
+            headers_only: true,
+            ..TemplateParser::new(mode, TemplateBuilder::default())
+        };
+        for (line_index, line) in data.lines().enumerate() {
+            parser.parse_line(line_index + 1, line)?;
+        }
+        Ok(parser.finish())
+    }
+
+    /// Parses a ```BmsTemplate``` while reading it line by line, so
+    /// the whole file never has to be in memory.
+    ///
+    /// Lines are decoded as ```encoding```, or as what
+    /// ```BmsEncoding::detect``` makes of the first 64 KiB of the
+    /// file (see ```read_lines```). The encoding that was used is
+    /// returned too.
+    pub fn parse_reader<B: BufRead>(
+        reader: B,
+        mode: BmsParseMode,
+        encoding: Option<BmsEncoding>,
+    ) -> Result<(BmsTemplate, Vec<Diagnostic>, BmsEncoding), BmsReadError> {
+        let mut parser = TemplateParser::new(mode, TemplateBuilder::default());
+        let encoding = read_lines(reader, encoding, |line_number, line| {
+            parser.parse_line(line_number, line)
+        })?;
+        let (template, diagnostics) = parser.finish();
+        Ok((template, diagnostics, encoding))
+    }
+
+    /// Builds a ```BmsChart``` out of the template, using ```rng```
+    /// to roll every ```#RANDOM``` and ```#SWITCH``` that's reached.
+    ///
+    /// Blocks inside branches that aren't taken never roll.
+    pub fn instantiate<R: BmsRng + ?Sized>(&self, rng: &mut R) -> BmsChart {
+        let mut chart = empty_chart(self.base);
+        let mut random_values = vec![];
+        instantiate_nodes(&self.nodes, rng, &mut random_values, &mut chart);
+        chart.update_objects();
+        chart
+    }
+}
+
+fn empty_chart(base: BmsBase) -> BmsChart {
+    BmsChart {
+        headers: HashMap::new(),
+        header_lines: vec![],
+        id_headers: HashMap::new(),
+        base,
+        objects: vec![],
+        time_signatures: HashMap::new(),
+        rolls: vec![],
+        encoding: None,
+    }
+}
+
+fn add_statement(chart: &mut BmsChart, statement: &BmsStatement) {
+    match statement {
+        BmsStatement::Header { name, value } => {
+            chart
+                .headers
+                .insert(UniCase::new(name.clone()), value.clone());
+            chart
+                .header_lines
+                .push((UniCase::new(name.clone()), value.clone()));
+        }
+        BmsStatement::TimeSignature { measure, value } => {
+            chart.time_signatures.insert(*measure, *value);
+        }
+        BmsStatement::Objects(objects) => chart.objects.extend_from_slice(objects),
+        BmsStatement::Definition { kind, id, value } => {
+            chart.id_headers.insert((*kind, *id), value.clone());
+        }
+    }
+}
+
+/// Resolves the value of a ```#RANDOM``` or ```#SWITCH```,
+/// keeping track of what was rolled
+fn resolve_value<R: BmsRng + ?Sized>(
+    line: usize,
+    value: BmsRandomValue,
+    rng: &mut R,
+    chart: &mut BmsChart,
+) -> u32 {
+    match value {
+        BmsRandomValue::Roll { max } => {
+            let value = rng.roll(max);
+            chart.rolls.push(BmsRoll { line, max, value });
+            value
+        }
+        BmsRandomValue::Set { value } => value,
+    }
+}
+
+/// Adds everything in ```nodes``` to the chart.
+///
+/// ```random_values``` has the value of every ```#RANDOM```
+/// the nodes are inside of, innermost last.
+fn instantiate_nodes<R: BmsRng + ?Sized>(
+    nodes: &[BmsTemplateNode],
+    rng: &mut R,
+    random_values: &mut Vec<u32>,
+    chart: &mut BmsChart,
+) {
+    for node in nodes {
+        match node {
+            BmsTemplateNode::Statement(statement) => add_statement(chart, statement),
+            BmsTemplateNode::Random { line, value, body } => {
+                let value = resolve_value(*line, *value, rng, chart);
+                random_values.push(value);
+                instantiate_nodes(body, rng, random_values, chart);
+                random_values.pop();
+            }
+            BmsTemplateNode::If { branches } => {
+                // Without a #RANDOM no branch is taken
+                let Some(random_value) = random_values.last() else {
+                    continue;
+                };
+                let taken = branches
+                    .iter()
+                    .find(|v| v.condition.is_none_or(|v| v == *random_value));
+                if let Some(branch) = taken {
+                    instantiate_nodes(&branch.body, rng, random_values, chart);
+                }
+            }
+            BmsTemplateNode::Switch { line, value, body } => {
+                let value = resolve_value(*line, *value, rng, chart);
+                // #DEF is only where it starts when no #CASE matches,
+                // wherever it is
+                let start = body
+                    .iter()
+                    .position(|v| *v == BmsSwitchItem::Case(value))
+                    .or_else(|| body.iter().position(|v| *v == BmsSwitchItem::Default));
+                let Some(start) = start else {
+                    continue;
+                };
+                // Fall through every label until a #SKIP
+                for item in &body[start..] {
+                    match item {
+                        BmsSwitchItem::Skip => break,
+                        BmsSwitchItem::Node(node) => {
+                            instantiate_nodes(std::slice::from_ref(node), rng, random_values, chart)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How much of a file ```read_lines``` detects the encoding from
+const DETECTION_PREFIX: usize = 64 * 1024;
+
+/// Reads ```reader``` line by line, giving every decoded line to
+/// ```parse_line``` along with its 1-based number, and returns the
+/// encoding the lines were decoded as.
+///
+/// Unless ```encoding``` is given, the encoding is detected from
+/// the lines in the first 64 KiB of the file, which are kept around
+/// until then. For any file smaller than that, it's the same
+/// encoding ```decode_chart``` detects from the whole file. Bigger
+/// files can only disagree with it when the text telling the
+/// encodings apart is all past the first 64 KiB.
+fn read_lines<B: BufRead>(
+    mut reader: B,
+    encoding: Option<BmsEncoding>,
+    mut parse_line: impl FnMut(usize, &str) -> Result<(), BmsParseError>,
+) -> Result<BmsEncoding, BmsReadError> {
+    let mut prefix = vec![];
+    if encoding.is_none() {
+        while prefix.len() < DETECTION_PREFIX && reader.read_until(b'\n', &mut prefix)? > 0 {}
+    }
+    let encoding = encoding.unwrap_or_else(|| BmsEncoding::detect(&prefix));
+
+    let mut line_number = 0;
+    let mut parse_bytes = |mut bytes: &[u8]| {
+        bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+        bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        if line_number == 0 {
+            bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+        }
+        // Stray CRs are line breaks too, just like in `decode_chart`
+        for bytes in bytes.split(|v| *v == b'\r') {
+            line_number += 1;
+            let line = if bytes.is_ascii() {
+                // ASCII reads the same in every encoding
+                Cow::Borrowed(std::str::from_utf8(bytes).unwrap())
+            } else {
+                encoding.decode(bytes)
+            };
+            parse_line(line_number, &line)?;
+        }
+        Ok::<(), BmsParseError>(())
+    };
+    for line in prefix.split_inclusive(|v| *v == b'\n') {
+        parse_bytes(line)?;
+    }
+    drop(prefix);
+    let mut buffer = vec![];
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+        parse_bytes(&buffer)?;
+    }
+    Ok(encoding)
+}
+
+/// Compiles a chart while reading it line by line, like parsing
+/// it with ```BmsTemplate::parse_reader``` and instantiating it
+/// would, but without ever having all of the file or its template
+/// in memory.
+pub(crate) fn compile_reader<B: BufRead, R: BmsRng + ?Sized>(
+    reader: B,
+    rng: &mut R,
+    mode: BmsParseMode,
+    encoding: Option<BmsEncoding>,
+) -> Result<(BmsChart, Vec<Diagnostic>), BmsReadError> {
+    let streamer = ChartStreamer {
+        rng,
+        chart: empty_chart(BmsBase::default()),
+        open: vec![],
+    };
+    let mut parser = TemplateParser::new(mode, streamer);
+    let encoding = read_lines(reader, encoding, |line_number, line| {
+        parser.parse_line(line_number, line)
+    })?;
+    let mut chart = parser.sink.finish();
+    chart.base = parser.base;
+    chart.encoding = Some(encoding);
+    Ok((chart, parser.diagnostics.into_list()))
+}
+
+/// Adds what it's given straight to a chart, rolling every
+/// ```#RANDOM``` and ```#SWITCH``` as soon as it's reached and
+/// skipping over the branches that aren't taken.
+///
+/// The chart comes out the same as instantiating the template of
+/// the same lines, with the same rolls in the same order.
+struct ChartStreamer<'a, R: BmsRng + ?Sized> {
+    rng: &'a mut R,
+    chart: BmsChart,
+    /// Every block that's open, innermost last
+    open: Vec<StreamBlock>,
+}
+
+/// A control flow block that is still open while streaming
+enum StreamBlock {
+    Random {
+        /// Whether what's in it goes in the chart
+        active: bool,
+        value: u32,
+    },
+    If {
+        /// Whether the block itself is somewhere that goes in the chart
+        parent_active: bool,
+        /// Whether a branch was taken, the current one included
+        taken: bool,
+        active: bool,
+    },
+    Switch {
+        parent_active: bool,
+        value: u32,
+        state: SwitchState,
+    },
+}
+
+/// Where a ```#SWITCH``` is at while streaming
+enum SwitchState {
+    /// Before the ```#CASE``` it starts at
+    Waiting,
+    /// After a ```#DEF```, along with everything since. It only runs
+    /// from there if no ```#CASE``` matches before the ```#ENDSW```,
+    /// so nothing in it goes in the chart until then.
+    Deferred(Vec<StreamItem>),
+    Running,
+    /// After a ```#SKIP```
+    Skipped,
+}
+
+/// Something given to the streamer, kept to be given again
+enum StreamItem {
+    Statement(BmsStatement),
+    Control {
+        line_number: usize,
+        control: BmsControl,
+        argument: Option<u32>,
+    },
+}
+
+impl<R: BmsRng + ?Sized> ChartStreamer<'_, R> {
+    /// Whether what's given goes in the chart
+    fn active(&self) -> bool {
+        match self.open.last() {
+            None => true,
+            Some(StreamBlock::Random { active, .. } | StreamBlock::If { active, .. }) => *active,
+            Some(StreamBlock::Switch {
+                parent_active,
+                state,
+                ..
+            }) => *parent_active && matches!(state, SwitchState::Running),
+        }
+    }
+
+    /// The value of the innermost ```#RANDOM```
+    fn random_value(&self) -> Option<u32> {
+        self.open.iter().rev().find_map(|v| match v {
+            StreamBlock::Random { value, .. } => Some(*value),
+            _ => None,
+        })
+    }
+
+    /// The ```#SWITCH``` that's waiting to know if it runs from its
+    /// ```#DEF```. Everything inside of it is inactive, so there's
+    /// at most one.
+    fn deferred(&self) -> Option<usize> {
+        self.open.iter().position(|v| {
+            matches!(
+                v,
+                StreamBlock::Switch {
+                    state: SwitchState::Deferred(_),
+                    ..
+                }
+            )
+        })
+    }
+
+    /// The innermost open block ```control``` continues or closes
+    fn target(&self, control: BmsControl) -> Option<usize> {
+        let is_block: fn(&StreamBlock) -> bool = match control {
+            BmsControl::EndRandom => |v| matches!(v, StreamBlock::Random { .. }),
+            BmsControl::ElseIf | BmsControl::Else | BmsControl::EndIf => {
+                |v| matches!(v, StreamBlock::If { .. })
+            }
+            BmsControl::Case | BmsControl::Skip | BmsControl::Def | BmsControl::EndSwitch => {
+                |v| matches!(v, StreamBlock::Switch { .. })
+            }
+            _ => return None,
+        };
+        self.open.iter().rposition(is_block)
+    }
+
+    /// Runs the deferred ```#SWITCH``` at ```index``` from its
+    /// ```#DEF```, as no ```#CASE``` of it matched
+    fn run_deferred(&mut self, index: usize) {
+        let Some(StreamBlock::Switch { state, .. }) = self.open.get_mut(index) else {
+            return;
+        };
+        let SwitchState::Deferred(items) = std::mem::replace(state, SwitchState::Running) else {
+            return;
+        };
+        self.open.truncate(index + 1);
+        for item in items {
+            match item {
+                StreamItem::Statement(statement) => self.statement(statement),
+                StreamItem::Control {
+                    line_number,
+                    control,
+                    argument,
+                } => {
+                    self.control(line_number, control, argument);
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> BmsChart {
+        while let Some(index) = self.deferred() {
+            self.run_deferred(index);
+        }
+        self.chart.update_objects();
+        self.chart
+    }
+}
+
+impl<R: BmsRng + ?Sized> TemplateSink for ChartStreamer<'_, R> {
+    fn statement(&mut self, statement: BmsStatement) {
+        if let Some(index) = self.deferred() {
+            if let StreamBlock::Switch {
+                state: SwitchState::Deferred(items),
+                ..
+            } = &mut self.open[index]
+            {
+                items.push(StreamItem::Statement(statement));
+            }
+        } else if self.active() {
+            add_statement(&mut self.chart, &statement);
+        }
+    }
+
+    fn control(&mut self, line_number: usize, control: BmsControl, argument: Option<u32>) -> bool {
+        if let Some(index) = self.deferred() {
+            let target = self.target(control);
+            let StreamBlock::Switch { value, state, .. } = &mut self.open[index] else {
+                unreachable!();
+            };
+            match target {
+                // The #CASE it starts at after all
+                Some(target)
+                    if target == index
+                        && argument == Some(*value)
+                        && control == BmsControl::Case =>
+                {
+                    *state = SwitchState::Running;
+                    self.open.truncate(index + 1);
+                    return true;
+                }
+                // Closes it, so it runs from the #DEF before that
+                Some(target)
+                    if target < index || (target == index && control == BmsControl::EndSwitch) =>
+                {
+                    self.run_deferred(index);
+                }
+                _ => {
+                    if let SwitchState::Deferred(items) = state {
+                        items.push(StreamItem::Control {
+                            line_number,
+                            control,
+                            argument,
+                        });
+                    }
+                }
+            }
+        }
+
+        match control {
+            BmsControl::Random
+            | BmsControl::SetRandom
+            | BmsControl::Switch
+            | BmsControl::SetSwitch => {
+                let parent_active = self.active();
+                // Blocks in branches that aren't taken never roll
+                let value = match parent_active {
+                    true => resolve_value(
+                        line_number,
+                        random_value(control, argument),
+                        self.rng,
+                        &mut self.chart,
+                    ),
+                    false => 0,
+                };
+                self.open.push(match control {
+                    BmsControl::Random | BmsControl::SetRandom => StreamBlock::Random {
+                        active: parent_active,
+                        value,
+                    },
+                    _ => StreamBlock::Switch {
+                        parent_active,
+                        value,
+                        state: SwitchState::Waiting,
+                    },
+                });
+                true
+            }
+            BmsControl::If => {
+                let parent_active = self.active();
+                // Without a #RANDOM no branch is taken
+                let active = parent_active && self.random_value() == Some(argument.unwrap_or(0));
+                self.open.push(StreamBlock::If {
+                    parent_active,
+                    taken: active,
+                    active,
+                });
+                true
+            }
+            BmsControl::ElseIf | BmsControl::Else => {
+                let Some(index) = self.target(control) else {
+                    return false;
+                };
+                self.open.truncate(index + 1);
+                let random_value = self.random_value();
+                if let Some(StreamBlock::If {
+                    parent_active,
+                    taken,
+                    active,
+                }) = self.open.last_mut()
+                {
+                    *active = *parent_active
+                        && !*taken
+                        && (control == BmsControl::Else
+                            || random_value == Some(argument.unwrap_or(0)));
+                    *taken |= *active;
+                }
+                true
+            }
+            BmsControl::Case | BmsControl::Skip | BmsControl::Def => {
+                if control == BmsControl::Case && argument.is_none() {
+                    // Already reported as an invalid argument
+                    return true;
+                }
+                let Some(index) = self.target(control) else {
+                    return false;
+                };
+                self.open.truncate(index + 1);
+                if let Some(StreamBlock::Switch {
+                    parent_active: true,
+                    value,
+                    state,
+                }) = self.open.last_mut()
+                {
+                    match (control, &state) {
+                        (BmsControl::Case, SwitchState::Waiting) if argument == Some(*value) => {
+                            *state = SwitchState::Running
+                        }
+                        (BmsControl::Def, SwitchState::Waiting) => {
+                            *state = SwitchState::Deferred(vec![])
+                        }
+                        (BmsControl::Skip, SwitchState::Running) => *state = SwitchState::Skipped,
+                        _ => {}
+                    }
+                }
+                true
+            }
+            BmsControl::EndRandom | BmsControl::EndIf | BmsControl::EndSwitch => {
+                let Some(index) = self.target(control) else {
+                    return false;
+                };
+                self.open.truncate(index);
+                true
+            }
+        }
+    }
+
+    fn in_random(&self) -> bool {
+        self.open
+            .iter()
+            .any(|v| matches!(v, StreamBlock::Random { .. }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::ReplayRng;
+
+    fn instantiate(data: &str, rolls: Vec<u32>) -> BmsChart {
+        BmsTemplate::parse(data)
+            .unwrap()
+            .instantiate(&mut ReplayRng::from_values(rolls))
+    }
+
+    fn header<'a>(chart: &'a BmsChart, name: &str) -> Option<&'a str> {
+        chart
+            .headers
+            .get(&UniCase::from(name.to_string()))
+            .map(|v| v.as_str())
+    }
+
+    /// The channel and value of every object, in order
+    fn objects(chart: &BmsChart) -> Vec<(u16, u16)> {
+        chart.objects.iter().map(|v| (v.channel, v.value)).collect()
+    }
+
+    #[test]
+    fn random_inside_a_branch_not_taken_never_rolls() {
+        let data = "#RANDOM 2\n#IF 1\n#RANDOM 4\n#IF 4\n#TITLE inner\n#ENDIF\n#ENDRANDOM\n#ENDIF\n#ENDRANDOM\n#RANDOM 3\n#IF 3\n#00111:01\n#ENDIF\n#ENDRANDOM";
+        let chart = instantiate(data, vec![2, 3]);
+        assert_eq!(
+            chart.rolls,
+            vec![
+                BmsRoll {
+                    line: 1,
+                    max: 2,
+                    value: 2
+                },
+                BmsRoll {
+                    line: 10,
+                    max: 3,
+                    value: 3
+                },
+            ]
+        );
+        assert_eq!(header(&chart, "TITLE"), None);
+        assert_eq!(objects(&chart), vec![(37, 1)]);
+
+        let chart = instantiate(data, vec![1, 4, 1]);
+        assert_eq!(chart.rolls.len(), 3);
+        assert_eq!(chart.rolls[1].line, 3);
+        assert_eq!(header(&chart, "TITLE"), Some("inner"));
+        assert_eq!(objects(&chart), vec![]);
+    }
+
+    #[test]
+    fn if_chain_takes_only_the_first_matching_branch() {
+        let data = "#RANDOM 4\n#IF 1\n#TITLE one\n#ELSEIF 2\n#TITLE two\n#ELSEIF 2\n#TITLE two again\n#ELSE\n#TITLE other\n#ENDIF\n#ENDRANDOM";
+        assert_eq!(header(&instantiate(data, vec![1]), "TITLE"), Some("one"));
+        assert_eq!(header(&instantiate(data, vec![2]), "TITLE"), Some("two"));
+        assert_eq!(header(&instantiate(data, vec![4]), "TITLE"), Some("other"));
+        let chart = instantiate(data, vec![2]);
+        assert_eq!(chart.header_lines.len(), 1);
+    }
+
+    #[test]
+    fn if_outside_of_a_random_is_an_error() {
+        let error = BmsTemplate::parse("#TITLE a\n#IF 1\n#TITLE one\n#ENDIF").unwrap_err();
+        assert!(matches!(error, BmsParseError::OrphanedIf { line: 2, .. }));
+    }
+
+    #[test]
+    fn switch_falls_through_until_a_skip() {
+        let data = "#SWITCH 3\n#CASE 1\n#00111:01\n#CASE 2\n#00112:02\n#SKIP\n#CASE 3\n#00113:03\n#DEF\n#00114:04\n#ENDSW";
+        assert_eq!(objects(&instantiate(data, vec![1])), vec![(37, 1), (38, 2)]);
+        assert_eq!(objects(&instantiate(data, vec![2])), vec![(38, 2)]);
+        // Falls through #DEF too
+        assert_eq!(objects(&instantiate(data, vec![3])), vec![(39, 3), (40, 4)]);
+
+        let chart = instantiate(data, vec![2]);
+        assert_eq!(
+            chart.rolls,
+            vec![BmsRoll {
+                line: 1,
+                max: 3,
+                value: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn switch_default_only_runs_without_a_matching_case() {
+        let data = "#SWITCH 3\n#DEF\n#TITLE def\n#SKIP\n#CASE 2\n#TITLE two\n#SKIP\n#ENDSW";
+        assert_eq!(header(&instantiate(data, vec![2]), "TITLE"), Some("two"));
+        assert_eq!(header(&instantiate(data, vec![3]), "TITLE"), Some("def"));
+    }
+
+    #[test]
+    fn set_random_and_set_switch_never_roll() {
+        let data = "#SETRANDOM 2\n#IF 2\n#TITLE two\n#ENDIF\n#ENDRANDOM\n#SETSWITCH 1\n#CASE 1\n#ARTIST one\n#SKIP\n#ENDSW";
+        let chart = instantiate(data, vec![1, 1]);
+        assert_eq!(chart.rolls, vec![]);
+        assert_eq!(header(&chart, "TITLE"), Some("two"));
+        assert_eq!(header(&chart, "ARTIST"), Some("one"));
+    }
+
+    #[test]
+    fn endif_closes_a_random_left_open_inside_it() {
+        let data = "#RANDOM 2\n#IF 1\n#RANDOM 2\n#TITLE inner\n#ENDIF\n#ARTIST outer\n#ENDRANDOM";
+        let chart = instantiate(data, vec![1, 2]);
+        assert_eq!(chart.rolls.len(), 2);
+        assert_eq!(header(&chart, "TITLE"), Some("inner"));
+        assert_eq!(header(&chart, "ARTIST"), Some("outer"));
+
+        // #ARTIST is after the #IF, so it's there either way
+        let chart = instantiate(data, vec![2]);
+        assert_eq!(chart.rolls.len(), 1);
+        assert_eq!(header(&chart, "TITLE"), None);
+        assert_eq!(header(&chart, "ARTIST"), Some("outer"));
+    }
+
+    #[test]
+    fn else_closes_a_random_left_open_inside_it() {
+        let data = "#RANDOM 2\n#IF 1\n#RANDOM 2\n#IF 1\n#TITLE x\n#ENDIF\n#ELSE\n#TITLE y\n#ENDIF\n#ENDRANDOM";
+        let chart = instantiate(data, vec![1, 2]);
+        assert_eq!(header(&chart, "TITLE"), None);
+        assert_eq!(chart.rolls.len(), 2);
+        let chart = instantiate(data, vec![2]);
+        assert_eq!(header(&chart, "TITLE"), Some("y"));
+        assert_eq!(chart.rolls.len(), 1);
+    }
+
+    #[test]
+    fn switch_labels_close_blocks_left_open_before_them() {
+        let data = "#SWITCH 2\n#CASE 1\n#RANDOM 2\n#IF 1\n#TITLE a\n#ENDIF\n#SKIP\n#CASE 2\n#ARTIST b\n#SKIP\n#ENDSW";
+        let chart = instantiate(data, vec![1, 1]);
+        assert_eq!(header(&chart, "TITLE"), Some("a"));
+        assert_eq!(header(&chart, "ARTIST"), None);
+        let chart = instantiate(data, vec![2]);
+        assert_eq!(header(&chart, "TITLE"), None);
+        assert_eq!(header(&chart, "ARTIST"), Some("b"));
+    }
+
+    /// The line and code of every diagnostic of a lenient parse
+    fn diagnostics(data: &str) -> Vec<(usize, &'static str)> {
+        let (_, diagnostics) = BmsTemplate::parse_with_mode(data, BmsParseMode::Lenient).unwrap();
+        diagnostics.iter().map(|v| (v.line, v.code)).collect()
+    }
+
+    #[test]
+    fn lenient_mode_skips_broken_lines_and_objects() {
+        let data = "#TITLE a\n#00111:01!!02\n#00x11:01\n#00102:abc\n#RANDOM x\n#IF 1\n#ENDIF\n#BASE 16\nnot a command";
+        assert_eq!(
+            diagnostics(data),
+            vec![
+                (2, "invalid_object"),
+                (3, "unknown_line"),
+                (4, "invalid_time_signature"),
+                (5, "invalid_random"),
+                (8, "unknown_base"),
+            ]
+        );
+        let (template, _) = BmsTemplate::parse_with_mode(data, BmsParseMode::Lenient).unwrap();
+        let chart = template.instantiate(&mut ReplayRng::from_values(vec![]));
+        // Only the broken object of the line is skipped
+        assert_eq!(objects(&chart), vec![(37, 1), (37, 2)]);
+        assert_eq!(header(&chart, "TITLE"), Some("a"));
+    }
+
+    #[test]
+    fn lenient_mode_reports_orphaned_ifs() {
+        assert_eq!(diagnostics("#IF 1\n#ENDIF"), vec![(1, "orphaned_if")]);
+    }
+
+    #[test]
+    fn lenient_mode_reports_unmatched_controls() {
+        assert_eq!(
+            diagnostics("#ELSE\n#CASE 1\n#ENDIF\n#ENDSW\n#SKIP\n#ELSEIF 2\n#DEF\n#ENDRANDOM"),
+            (1..=8)
+                .map(|v| (v, "unmatched_control"))
+                .collect::<Vec<_>>()
+        );
+        // Labels of a #SWITCH aren't matched by an #IF inside of it
+        assert_eq!(
+            diagnostics("#SWITCH 2\n#RANDOM 2\n#IF 1\n#ELSE\n#ENDIF\n#ENDRANDOM\n#CASE 1\n#ENDSW"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn streaming_compiles_like_instantiating_the_template() {
+        let charts = [
+            // Nested blocks, the inner one in a branch that isn't taken
+            "#RANDOM 2\n#IF 1\n#RANDOM 4\n#IF 4\n#TITLE inner\n#ENDIF\n#ENDRANDOM\n#ELSEIF 2\n#00111:01\n#ELSE\n#00112:01\n#ENDIF\n#ENDRANDOM\n#RANDOM 3\n#IF 3\n#00113:01\n#ENDIF\n#ENDRANDOM",
+            // #DEF before the #CASE that matches, and one that doesn't
+            "#SWITCH 3\n#DEF\n#RANDOM 2\n#IF 2\n#00111:01\n#ENDIF\n#ENDRANDOM\n#SKIP\n#CASE 2\n#00112:01\n#CASE 3\n#00113:01\n#ENDSW\n#SWITCH 2\n#CASE 1\n#00114:01\n#DEF\n#RANDOM 2\n#00115:01\n#ENDSW",
+            // Blocks left open and closed by the blocks around them
+            "#RANDOM 2\n#IF 2\n#SWITCH 2\n#CASE 2\n#RANDOM 2\n#IF 1\n#ELSE\n#00111:01\n#ENDIF\n#00112:01\n#ELSE\n#00113:01\n#ENDIF\n#00114:01\n#SWITCH 2\n#DEF\n#00115:01\n#ENDRANDOM\n#00116:01\n#RANDOM 2",
+        ];
+        let rolls = vec![2, 3, 2, 1, 2, 2, 2];
+        for data in charts {
+            let template_chart = instantiate(data, rolls.clone());
+            let (chart, _) = compile_reader(
+                data.as_bytes(),
+                &mut ReplayRng::from_values(rolls.clone()),
+                BmsParseMode::Strict,
+                None,
+            )
+            .unwrap();
+            assert_eq!(chart.rolls, template_chart.rolls, "{data}");
+            assert_eq!(chart.header_lines, template_chart.header_lines, "{data}");
+            assert_eq!(objects(&chart), objects(&template_chart), "{data}");
+        }
+    }
+
+    #[test]
+    fn reading_detects_the_encoding_of_the_whole_prefix() {
+        // Alone, the half-width katakana of the first line would pass
+        // for EUC-KR
+        let (mut data, _, _) = encoding_rs::SHIFT_JIS.encode("#ARTIST ｱｲ\n#TITLE テスト\n");
+        data.to_mut().extend_from_slice(b"#00111:01\n");
+        let (chart, _) = compile_reader(
+            &data[..],
+            &mut ReplayRng::from_values(vec![]),
+            BmsParseMode::Strict,
+            None,
+        )
+        .unwrap();
+        assert_eq!(chart.encoding, Some(BmsEncoding::ShiftJis));
+        assert_eq!(header(&chart, "ARTIST"), Some("ｱｲ"));
+        assert_eq!(header(&chart, "TITLE"), Some("テスト"));
+        assert_eq!(objects(&chart), vec![(37, 1)]);
+    }
+
+    #[test]
+    fn base_62_ids_tell_cases_apart() {
+        let data = "#BASE 62\n#WAVaa a.wav\n#WAVAA b.wav\n#0011A:aaAA";
+        let chart = instantiate(data, vec![]);
+        assert_eq!(chart.base, BmsBase::Base62);
+        let aa = 36 * 62 + 36;
+        let upper_aa = 10 * 62 + 10;
+        let wav = |id| {
+            chart
+                .id_headers
+                .get(&(BmsIdKind::Wav, id))
+                .map(|v| v.as_str())
+        };
+        assert_eq!(wav(aa), Some("a.wav"));
+        assert_eq!(wav(upper_aa), Some("b.wav"));
+        // The channel is always base 36
+        assert_eq!(objects(&chart), vec![(46, aa), (46, upper_aa)]);
+    }
+
+    #[test]
+    fn channel_03_values_are_hexadecimal() {
+        for base in ["36", "62"] {
+            let chart = instantiate(&format!("#BASE {base}\n#00103:FF78"), vec![]);
+            assert_eq!(objects(&chart), vec![(3, 255), (3, 120)]);
+        }
+    }
+}
@@ -1,13 +1,13 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::hash::Hash;
+use std::fmt;
 use std::ops::{Add, Sub};
 
-use num::BigUint;
 use ordered_float::OrderedFloat;
-use regex::Regex;
 use unicase::UniCase;
 
 use super::chart::BmsChart;
+use super::lexer::BmsIdKind;
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, PartialOrd, Ord, Default)]
 pub struct BmsTime {
@@ -17,10 +17,7 @@ pub struct BmsTime {
 
 impl BmsTime {
     pub fn new_ordered(measure: u16, fraction: OrderedFloat<f64>) -> Self {
-        let mut output = BmsTime {
-            measure,
-            fraction: fraction,
-        };
+        let mut output = BmsTime { measure, fraction };
         output.measure = (output.measure as i16 + output.fraction.floor() as i16) as u16;
         output.fraction %= 1.0;
         output.fraction = OrderedFloat(output.fraction.abs());
@@ -56,48 +53,63 @@ pub struct BmsTiming {
     pub scroll_changes: HashMap<BmsTime, f64>,
 }
 
-// TODO: Name this function better
-fn regex_header_thing<T: num_traits::Num + Eq + Hash, J: std::str::FromStr>(
-    headers: &HashMap<UniCase<String>, String>,
-    regex: &Regex,
-) -> Option<HashMap<T, J>> {
+/// An error found while generating a ```BmsTiming``` from a ```BmsChart```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BmsTimingError {
+    /// The chart doesn't have a ```#BPM``` header
+    MissingBpm,
+    /// A ```#BPM```, ```#BPMxx```, ```#STOPxx``` or ```#SCROLLxx```
+    /// header doesn't have a valid number as its value
+    InvalidHeader { name: String, value: String },
+}
+
+impl fmt::Display for BmsTimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingBpm => write!(f, "missing #BPM header"),
+            Self::InvalidHeader { name, value } => {
+                write!(f, "invalid value \"{}\" for header #{}", value, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BmsTimingError {}
+
+/// Parses the value of every ```kind``` header into a map from id to value
+fn parse_id_headers<J: std::str::FromStr>(
+    chart: &BmsChart,
+    kind: BmsIdKind,
+) -> Result<HashMap<u16, J>, BmsTimingError> {
     let mut out = HashMap::new();
-    for key in headers.keys() {
-        let lowercase_key = key.to_lowercase();
-        let captures = match regex.captures(&lowercase_key) {
-            Some(v) => v,
-            None => continue,
-        };
-        let id = match T::from_str_radix(&captures[1], 36) {
-            Ok(v) => v,
-            Err(_) => continue,
-        };
-        let value: J = match str::parse(headers.get(key).unwrap()) {
+    for ((header_kind, id), value) in &chart.id_headers {
+        if *header_kind != kind {
+            continue;
+        }
+        let parsed: J = match str::parse(value) {
             Ok(v) => v,
-            Err(_) => return None,
+            Err(_) => {
+                return Err(BmsTimingError::InvalidHeader {
+                    name: format!("{}{}", kind.prefix(), chart.base.encode_id(*id)),
+                    value: value.clone(),
+                })
+            }
         };
-        out.insert(id, value);
+        out.insert(*id, parsed);
     }
-    Some(out)
+    Ok(out)
 }
 
 // TODO: Clean up
-pub fn generate_timings(chart: &BmsChart) -> Option<BmsTiming> {
-    let bpm_regex = Regex::new(r"^bpm(\d{2})$").unwrap();
-    let bpm_ids: HashMap<u16, f64> = match regex_header_thing(&chart.headers, &bpm_regex) {
-        Some(v) => v,
-        None => return None,
-    };
-    let stop_regex = Regex::new(r"^stop(\d{2})$").unwrap();
-    let stop_ids: HashMap<u16, f64> = match regex_header_thing(&chart.headers, &stop_regex) {
-        Some(v) => v,
-        None => return None,
-    };
-    let scroll_regex = Regex::new(r"^scroll(\d{2})$").unwrap();
-    let scroll_ids: HashMap<u16, f64> = match regex_header_thing(&chart.headers, &scroll_regex) {
-        Some(v) => v,
-        None => return None,
-    };
+/// Collects the BPM changes, stops and scroll changes of a chart.
+///
+/// Channel 08, 09 and SC objects are looked up in the ```#BPMxx```,
+/// ```#STOPxx``` and ```#SCROLLxx``` headers, while the ```value```
+/// of a channel 03 object is already the BPM.
+pub fn generate_timings(chart: &BmsChart) -> Result<BmsTiming, BmsTimingError> {
+    let bpm_ids: HashMap<u16, f64> = parse_id_headers(chart, BmsIdKind::Bpm)?;
+    let stop_ids: HashMap<u16, f64> = parse_id_headers(chart, BmsIdKind::Stop)?;
+    let scroll_ids: HashMap<u16, f64> = parse_id_headers(chart, BmsIdKind::Scroll)?;
     let mut bpm_changes: HashMap<BmsTime, f64> = chart
         .objects
         .iter()
@@ -105,15 +117,8 @@ pub fn generate_timings(chart: &BmsChart) -> Option<BmsTiming> {
             object.channel == 3 || (object.channel == 8 && bpm_ids.contains_key(&object.value))
         })
         .map(|object| match object.channel {
-            3 => {
-                // Shitty horrible thing that must be done because
-                // on channel 3, values are in base 16, but we took it
-                // as a base 36 value, so we undo the convertion then
-                // re-parse it as base 16
-                let bpm_str = BigUint::from(object.value).to_str_radix(36);
-                let bpm = u8::from_str_radix(&bpm_str, 16).unwrap();
-                (object.time, bpm as f64)
-            }
+            // Channel 03 BPMs are decoded from hexadecimal while parsing
+            3 => (object.time, object.value as f64),
             8 => (object.time, *bpm_ids.get(&object.value).unwrap()),
             _ => unreachable!(),
         })
@@ -122,15 +127,19 @@ pub fn generate_timings(chart: &BmsChart) -> Option<BmsTiming> {
         measure: 0,
         fraction: OrderedFloat(0.0),
     };
-    if bpm_changes.contains_key(&start) == false {
-        bpm_changes.insert(
-            start,
+    if let Entry::Vacant(entry) = bpm_changes.entry(start) {
+        entry.insert(
             match chart.headers.get(&UniCase::new("BPM".to_string())) {
                 Some(v) => match v.parse() {
                     Ok(v) => v,
-                    Err(_) => return None,
+                    Err(_) => {
+                        return Err(BmsTimingError::InvalidHeader {
+                            name: "BPM".to_string(),
+                            value: v.clone(),
+                        })
+                    }
                 },
-                None => return None,
+                None => return Err(BmsTimingError::MissingBpm),
             },
         );
     }
@@ -152,118 +161,306 @@ pub fn generate_timings(chart: &BmsChart) -> Option<BmsTiming> {
         stops,
         scroll_changes,
     };
-    Some(timing)
+    Ok(timing)
 }
 
-fn process_bpm_change(
-    new_bpm: f64,
-    time_sig: f64,
+/// Converts ```BmsTime```s to seconds after the chart starts,
+/// with every BPM change and stop worked out once up front.
+///
+/// A stop delays everything after it, but not what's at its own
+/// time, which is hit right as the chart stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingMap {
+    /// Where each measure starts, in beats
+    measure_starts: Vec<f64>,
+    /// How many beats each measure lasts
+    measure_lengths: Vec<f64>,
+    initial_bpm: f64,
+    /// Where the BPM changes or the chart stops, in order
+    segments: Vec<TimingSegment>,
+}
+
+/// How the chart moves from a BPM change or stop until the next one
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TimingSegment {
+    time: BmsTime,
     beats: f64,
-    change_time: BmsTime,
-    last_change_seconds: &mut f64,
-    last_change_beats: &mut f64,
-    current_bpm: &mut f64,
-) {
-    let current_beats = beats + time_sig * 4.0 * change_time.fraction.0;
-    *last_change_seconds += (current_beats - *last_change_beats) * (60.0 / *current_bpm);
-    *last_change_beats = current_beats;
-    *current_bpm = new_bpm;
+    /// Seconds at ```time```, stops at ```time``` included
+    seconds: f64,
+    bpm: f64,
 }
 
-impl BmsTime {
-    pub fn to_seconds(
-        &self,
+impl TimingMap {
+    pub fn new(timing: &BmsTiming, time_signatures: &HashMap<u16, f64>) -> Self {
+        Self::from_changes(&timing.bpm_changes, &timing.stops, time_signatures)
+    }
+
+    /// ```TimingMap::new``` with the parts of a ```BmsTiming```.
+    ///
+    /// The earliest of ```bpm_changes``` is the BPM the chart starts
+    /// with, and 130 (the default of ```#BPM```) if there's none.
+    pub fn from_changes(
         bpm_changes: &HashMap<BmsTime, f64>,
         stops: &HashMap<BmsTime, f64>,
         time_signatures: &HashMap<u16, f64>,
-    ) -> f64 {
-        let mut bpm_change_times: Vec<&BmsTime> = bpm_changes.keys().collect();
-        bpm_change_times.sort();
-        bpm_change_times.reverse();
-
-        let mut stop_times: Vec<&BmsTime> = stops.keys().collect();
-        stop_times.sort();
-        stop_times.reverse();
-
-        let mut current_bpm = *bpm_changes.get(bpm_change_times.pop().unwrap()).unwrap();
-        let mut last_change_beats = 0.0;
-        let mut last_change_seconds = 0.0;
-        let mut seconds_stoped = 0.0;
-        let mut time_sig = 1.0;
+    ) -> Self {
+        let mut changes: Vec<(BmsTime, Option<f64>, Option<f64>)> = bpm_changes
+            .iter()
+            .map(|(time, bpm)| (*time, Some(*bpm), None))
+            .chain(stops.iter().map(|(time, stop)| (*time, None, Some(*stop))))
+            .collect();
+        changes.sort_by_key(|(time, ..)| *time);
+
+        // Every measure up to the last one with something in it,
+        // measures after that are 4/4
+        let last_measure = changes
+            .iter()
+            .map(|(time, ..)| time.measure)
+            .chain(time_signatures.keys().copied())
+            .max()
+            .unwrap_or(0);
+        let measure_lengths: Vec<f64> = (0..=last_measure)
+            .map(|measure| 4.0 * time_signatures.get(&measure).unwrap_or(&1.0))
+            .collect();
+        let mut measure_starts = Vec::with_capacity(measure_lengths.len());
         let mut beats = 0.0;
+        for length in &measure_lengths {
+            measure_starts.push(beats);
+            beats += length;
+        }
 
-        for i in 0..self.measure {
-            time_sig = *time_signatures.get(&i).unwrap_or(&1.0);
-            let time = BmsTime {
-                measure: i + 1,
-                ..Default::default()
-            };
-            loop {
-                if let Some(next_stop_time) = stop_times.last() {
-                    if let Some(next_bpm_change_time) = bpm_change_times.last() {
-                        if next_stop_time < next_bpm_change_time && time > **next_stop_time {
-                            seconds_stoped += (60.0 / current_bpm)
-                                * (stops.get(next_stop_time).unwrap() / 192.0)
-                                * 4.0;
-                            stop_times.pop();
-                        } else if time > **next_bpm_change_time {
-                            process_bpm_change(
-                                *bpm_changes.get(next_bpm_change_time).unwrap(),
-                                time_sig,
-                                beats,
-                                **next_bpm_change_time,
-                                &mut last_change_seconds,
-                                &mut last_change_beats,
-                                &mut current_bpm,
-                            );
-                            bpm_change_times.pop();
-                        } else {
-                            break;
-                        }
-                    } else if time > **next_stop_time {
-                        seconds_stoped += (60.0 / current_bpm)
-                            * (stops.get(next_stop_time).unwrap() / 192.0)
-                            * 4.0;
-                        stop_times.pop();
-                    } else {
-                        break;
-                    }
-                } else if let Some(next_bpm_change_time) = bpm_change_times.last() {
-                    if time > **next_bpm_change_time {
-                        process_bpm_change(
-                            *bpm_changes.get(next_bpm_change_time).unwrap(),
-                            time_sig,
-                            beats,
-                            **next_bpm_change_time,
-                            &mut last_change_seconds,
-                            &mut last_change_beats,
-                            &mut current_bpm,
-                        );
-                        bpm_change_times.pop();
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
+        let mut map = TimingMap {
+            measure_starts,
+            measure_lengths,
+            initial_bpm: changes.iter().find_map(|(_, bpm, _)| *bpm).unwrap_or(130.0),
+            segments: Vec::with_capacity(changes.len()),
+        };
+        for (time, bpm, stop) in changes {
+            let beats = map.beats(time);
+            let seconds = map.seconds(time);
+            let segment = match map.segments.last_mut() {
+                // A BPM change and a stop at the same time, where
+                // the stop lasts as long as the new BPM says
+                Some(last) if last.time == time => last,
+                _ => {
+                    map.segments.push(TimingSegment {
+                        time,
+                        beats,
+                        seconds,
+                        bpm: map.segment_at(time).bpm,
+                    });
+                    map.segments.last_mut().unwrap()
                 }
+            };
+            if let Some(bpm) = bpm {
+                segment.bpm = bpm;
+            }
+            if let Some(stop) = stop {
+                // Stops are in 192nds of a 4/4 measure
+                segment.seconds += (stop / 192.0) * 4.0 * (60.0 / segment.bpm);
             }
-            beats += 4.0 * time_sig;
         }
-        // Process the last change in time signature without adding the measure to beats
-        while let Some(next_bpm_change_time) = bpm_change_times.last() {
-            if self > *next_bpm_change_time {
-                let current_beats = beats + time_sig * 4.0 * next_bpm_change_time.fraction.0;
-                last_change_seconds += (current_beats - last_change_beats) * (60.0 / current_bpm);
-                last_change_beats = current_beats;
-                current_bpm = *bpm_changes.get(next_bpm_change_time).unwrap();
-                bpm_change_times.pop();
-            } else {
-                break;
+        map
+    }
+
+    /// How many beats there are from the start of the chart to ```time```
+    pub fn beats(&self, time: BmsTime) -> f64 {
+        let measure = time.measure as usize;
+        let (start, length) = match self.measure_starts.get(measure) {
+            Some(start) => (*start, self.measure_lengths[measure]),
+            None => {
+                let last = self.measure_starts.len() - 1;
+                let end = self.measure_starts[last] + self.measure_lengths[last];
+                (end + 4.0 * (measure - last - 1) as f64, 4.0)
             }
+        };
+        start + time.fraction.0 * length
+    }
+
+    /// The segment ```time``` is in, which is the last one that
+    /// starts strictly before it
+    fn segment_at(&self, time: BmsTime) -> TimingSegment {
+        let index = self.segments.partition_point(|v| v.time < time);
+        self.segment_before(index)
+    }
+
+    fn segment_before(&self, index: usize) -> TimingSegment {
+        match index {
+            0 => TimingSegment {
+                time: BmsTime::default(),
+                beats: 0.0,
+                seconds: 0.0,
+                bpm: self.initial_bpm,
+            },
+            _ => self.segments[index - 1],
         }
-        time_sig = *time_signatures.get(&self.measure).unwrap_or(&1.0);
-        beats += 4.0 * self.fraction.0 * time_sig;
+    }
+
+    fn seconds_in(&self, segment: TimingSegment, time: BmsTime) -> f64 {
+        segment.seconds + (self.beats(time) - segment.beats) * (60.0 / segment.bpm)
+    }
+
+    /// Seconds after the start of the chart ```time``` is at
+    pub fn seconds(&self, time: BmsTime) -> f64 {
+        self.seconds_in(self.segment_at(time), time)
+    }
+
+    /// ```seconds``` of each of ```times```, which have to be in
+    /// order, going through the segments only once
+    pub fn seconds_sorted<'a>(
+        &'a self,
+        times: impl IntoIterator<Item = BmsTime> + 'a,
+    ) -> impl Iterator<Item = f64> + 'a {
+        let mut index = 0;
+        times
+            .into_iter()
+            .map(move |time| self.seconds_from(&mut index, time))
+    }
+
+    /// ```seconds_sorted```, keeping the ```TimingMap``` in the
+    /// returned iterator instead of borrowing it
+    pub fn into_seconds_sorted<'a>(
+        self,
+        times: impl IntoIterator<Item = BmsTime> + 'a,
+    ) -> impl Iterator<Item = f64> + 'a {
+        let mut index = 0;
+        times
+            .into_iter()
+            .map(move |time| self.seconds_from(&mut index, time))
+    }
+
+    /// ```seconds``` of ```time```, looking for its segment from
+    /// ```index``` on and leaving ```index``` at it
+    fn seconds_from(&self, index: &mut usize, time: BmsTime) -> f64 {
+        while *index < self.segments.len() && self.segments[*index].time < time {
+            *index += 1;
+        }
+        self.seconds_in(self.segment_before(*index), time)
+    }
+}
+
+impl BmsTime {
+    /// Seconds after the start of the chart this is at.
+    ///
+    /// This builds a ```TimingMap``` every time, so build one
+    /// instead when converting more than one time.
+    pub fn to_seconds(
+        &self,
+        bpm_changes: &HashMap<BmsTime, f64>,
+        stops: &HashMap<BmsTime, f64>,
+        time_signatures: &HashMap<u16, f64>,
+    ) -> f64 {
+        TimingMap::from_changes(bpm_changes, stops, time_signatures).seconds(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing_map(
+        bpm_changes: &[(BmsTime, f64)],
+        stops: &[(BmsTime, f64)],
+        time_signatures: &[(u16, f64)],
+    ) -> TimingMap {
+        TimingMap::from_changes(
+            &bpm_changes.iter().copied().collect(),
+            &stops.iter().copied().collect(),
+            &time_signatures.iter().copied().collect(),
+        )
+    }
+
+    #[test]
+    fn bpm_changes_mid_measure_use_its_time_signature() {
+        // Measure 1 is 3/4, and the BPM halves a beat into it
+        let map = timing_map(
+            &[
+                (BmsTime::new(0, 0.0), 120.0),
+                (BmsTime::new(1, 1.0 / 3.0), 60.0),
+            ],
+            &[],
+            &[(1, 0.75)],
+        );
+        assert_eq!(map.seconds(BmsTime::new(1, 0.0)), 2.0);
+        assert_eq!(map.seconds(BmsTime::new(1, 1.0 / 3.0)), 2.5);
+        assert_eq!(map.seconds(BmsTime::new(1, 2.0 / 3.0)), 3.5);
+        assert_eq!(map.seconds(BmsTime::new(2, 0.0)), 4.5);
+    }
+
+    #[test]
+    fn stops_last_as_long_as_the_bpm_changed_at_the_same_time_says() {
+        let map = timing_map(
+            &[(BmsTime::new(0, 0.0), 120.0), (BmsTime::new(1, 0.0), 240.0)],
+            // A whole 4/4 measure, so 1 second at 240 BPM
+            &[(BmsTime::new(1, 0.0), 192.0)],
+            &[],
+        );
+        assert_eq!(map.seconds(BmsTime::new(1, 0.25)), 3.25);
+        assert_eq!(map.seconds(BmsTime::new(2, 0.0)), 4.0);
+    }
 
-        (beats - last_change_beats) * (60.0 / current_bpm) + last_change_seconds + seconds_stoped
+    #[test]
+    fn times_at_a_stop_do_not_include_it() {
+        let map = timing_map(
+            &[(BmsTime::new(0, 0.0), 120.0)],
+            &[(BmsTime::new(1, 0.5), 96.0)],
+            &[],
+        );
+        assert_eq!(map.seconds(BmsTime::new(1, 0.5)), 3.0);
+        // An earlier stop in the same measure still counts
+        assert_eq!(map.seconds(BmsTime::new(1, 0.75)), 4.5);
+    }
+
+    #[test]
+    fn measures_after_the_last_change_are_4_4() {
+        let map = timing_map(
+            &[(BmsTime::new(0, 0.0), 60.0), (BmsTime::new(2, 0.0), 120.0)],
+            &[],
+            &[(0, 0.5)],
+        );
+        // 2 beats at 60, 4 beats at 60, then 8 and a half measures at 120
+        assert_eq!(map.seconds(BmsTime::new(2, 0.0)), 6.0);
+        assert_eq!(map.seconds(BmsTime::new(10, 0.5)), 23.0);
+        assert_eq!(map.beats(BmsTime::new(10, 0.5)), 40.0);
+    }
+
+    #[test]
+    fn seconds_sorted_is_the_same_as_seconds() {
+        let map = timing_map(
+            &[
+                (BmsTime::new(0, 0.0), 150.0),
+                (BmsTime::new(1, 0.5), 75.0),
+                (BmsTime::new(3, 0.25), 300.0),
+                (BmsTime::new(3, 0.75), 180.0),
+            ],
+            &[
+                (BmsTime::new(1, 0.5), 48.0),
+                (BmsTime::new(2, 0.125), 192.0),
+                (BmsTime::new(4, 0.0), 24.0),
+            ],
+            &[(1, 1.5), (3, 0.25)],
+        );
+        let mut times: Vec<BmsTime> = (0..6)
+            .flat_map(|measure| (0..16).map(move |i| BmsTime::new(measure, i as f64 / 16.0)))
+            .collect();
+        times.extend([BmsTime::new(1, 0.5), BmsTime::new(4, 0.0)]);
+        times.sort();
+        let sorted: Vec<f64> = map.seconds_sorted(times.iter().copied()).collect();
+        let each: Vec<f64> = times.iter().map(|v| map.seconds(*v)).collect();
+        assert_eq!(sorted, each);
+        let owned: Vec<f64> = map.into_seconds_sorted(times).collect();
+        assert_eq!(owned, each);
+    }
+
+    #[test]
+    fn to_seconds_is_the_same_as_timing_map() {
+        let bpm_changes = HashMap::from([(BmsTime::new(0, 0.0), 120.0)]);
+        let stops = HashMap::from([(BmsTime::new(1, 0.5), 96.0)]);
+        let time_signatures = HashMap::from([(1, 0.5)]);
+        let time = BmsTime::new(2, 0.25);
+        assert_eq!(
+            time.to_seconds(&bpm_changes, &stops, &time_signatures),
+            TimingMap::from_changes(&bpm_changes, &stops, &time_signatures).seconds(time)
+        );
     }
 }
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use crate::{
+    chart::{BmsChart, BmsObject, BmsParseError},
+    rng::ReplayRng,
+    template::BmsTemplate,
+    timing::BmsTime,
+};
+
+/// One of the charts a ```#RANDOM``` chart can compile to
+#[derive(Debug)]
+pub struct BmsVariant {
+    pub chart: BmsChart,
+    /// The values rolled to get this variant, in the same
+    /// order as ```chart.rolls```
+    pub rolls: Vec<u32>,
+    /// How likely this variant is with a fair rng
+    pub probability: f64,
+}
+
+/// Every chart a ```#RANDOM``` chart can compile to,
+/// see ```BmsChart::compile_variants```
+#[derive(Debug)]
+pub struct BmsVariants {
+    pub variants: Vec<BmsVariant>,
+    /// False when there were more variants than the limit
+    /// and some of them were left out
+    pub complete: bool,
+}
+
+impl BmsVariants {
+    /// How likely each object is to show up in the compiled chart,
+    /// in the order they first appear in the variants.
+    ///
+    /// Only objects from the enumerated variants are counted, so if
+    /// the enumeration isn't ```complete``` the probabilities are
+    /// lower bounds.
+    pub fn object_probabilities(&self) -> Vec<(BmsObject, f64)> {
+        // BmsObject equality ignores the value, so key on every field instead
+        let mut indices: HashMap<(u16, BmsTime, u16), usize> = HashMap::new();
+        let mut probabilities: Vec<(BmsObject, f64)> = vec![];
+        for variant in &self.variants {
+            for object in &variant.chart.objects {
+                let key = (object.channel, object.time, object.value);
+                let index = *indices.entry(key).or_insert_with(|| {
+                    probabilities.push((*object, 0.0));
+                    probabilities.len() - 1
+                });
+                probabilities[index].1 += variant.probability;
+            }
+        }
+        probabilities
+    }
+}
+
+impl BmsChart {
+    /// Compiles every chart ```data``` can produce by walking every
+    /// combination of ```#RANDOM``` and ```#SWITCH``` values, nested
+    /// blocks included.
+    ///
+    /// Stops after ```max_variants``` charts, in which case the
+    /// result isn't ```complete```. A ```max_variants``` of 0 means
+    /// there's no limit.
+    pub fn compile_variants(data: &str, max_variants: usize) -> Result<BmsVariants, BmsParseError> {
+        Ok(BmsTemplate::parse(data)?.instantiate_variants(max_variants))
+    }
+}
+
+impl BmsTemplate {
+    /// Instantiates every chart the template can produce, see
+    /// ```BmsChart::compile_variants```
+    pub fn instantiate_variants(&self, max_variants: usize) -> BmsVariants {
+        let mut variants = vec![];
+        // The values to roll first, anything after them rolls 1
+        let mut prefix: Vec<u32> = vec![];
+        loop {
+            if max_variants != 0 && variants.len() >= max_variants {
+                return BmsVariants {
+                    variants,
+                    complete: false,
+                };
+            }
+
+            let chart = self.instantiate(&mut ReplayRng::from_values(prefix));
+            let rolls: Vec<u32> = chart.rolls.iter().map(|v| v.value).collect();
+            let probability = chart
+                .rolls
+                .iter()
+                .map(|v| 1.0 / v.max.max(1) as f64)
+                .product();
+
+            // Count up like an odometer, the rolls after the one that
+            // changed may not even happen anymore so they get dropped
+            let next = chart.rolls.iter().rposition(|v| v.value < v.max);
+            prefix = match next {
+                Some(i) => {
+                    let mut prefix = rolls[..i].to_vec();
+                    prefix.push(rolls[i] + 1);
+                    prefix
+                }
+                None => vec![],
+            };
+
+            variants.push(BmsVariant {
+                chart,
+                rolls,
+                probability,
+            });
+
+            if next.is_none() {
+                return BmsVariants {
+                    variants,
+                    complete: true,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// #RANDOM 3 nested in the first branch of a #RANDOM 2
+    const NESTED: &str = "#RANDOM 2\n#IF 1\n#RANDOM 3\n#IF 1\n#00111:01\n#ENDIF\n#ENDRANDOM\n#ENDIF\n#IF 2\n#00112:02\n#ENDIF\n#ENDRANDOM";
+
+    #[test]
+    fn nested_variants_add_up_to_one() {
+        let variants = BmsChart::compile_variants(NESTED, 100).unwrap();
+        assert!(variants.complete);
+        let rolls: Vec<Vec<u32>> = variants.variants.iter().map(|v| v.rolls.clone()).collect();
+        assert_eq!(rolls, vec![vec![1, 1], vec![1, 2], vec![1, 3], vec![2]]);
+        let probabilities: Vec<f64> = variants.variants.iter().map(|v| v.probability).collect();
+        assert_eq!(probabilities, vec![1.0 / 6.0, 1.0 / 6.0, 1.0 / 6.0, 0.5]);
+        let total: f64 = probabilities.iter().sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        let objects: Vec<(u16, u16, f64)> = variants
+            .object_probabilities()
+            .into_iter()
+            .map(|(object, probability)| (object.channel, object.value, probability))
+            .collect();
+        assert_eq!(objects, vec![(37, 1, 1.0 / 6.0), (38, 2, 0.5)]);
+    }
+
+    #[test]
+    fn variants_stop_at_the_limit() {
+        let variants = BmsChart::compile_variants(NESTED, 2).unwrap();
+        assert!(!variants.complete);
+        assert_eq!(variants.variants.len(), 2);
+
+        // Exactly as many variants as the limit is still complete
+        let variants = BmsChart::compile_variants(NESTED, 4).unwrap();
+        assert!(variants.complete);
+        assert_eq!(variants.variants.len(), 4);
+
+        // No limit at all
+        let variants = BmsChart::compile_variants(NESTED, 0).unwrap();
+        assert!(variants.complete);
+        assert_eq!(variants.variants.len(), 4);
+        let variants = BmsChart::compile_variants("#00111:01", 0).unwrap();
+        assert!(variants.complete);
+        assert_eq!(variants.variants.len(), 1);
+        assert_eq!(variants.variants[0].probability, 1.0);
+    }
+}